@@ -0,0 +1,37 @@
+use std::env;
+use std::fmt::Write;
+use std::path::Path;
+
+mod schema;
+
+fn main() {
+    println!("cargo:rerun-if-changed=schema.rs");
+    let sources: Vec<String> = schema::SOURCES
+        .iter()
+        .map(|path| {
+            println!("cargo:rerun-if-changed={path}");
+            read(path)
+        })
+        .collect();
+    let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "/// Derived from the definitions of the types sent by the web config protocol, so that any change to them is detected, even if `PROTOCOL_VERSION` was not incremented."
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "pub const SCHEMA_HASH: u32 = {:#010x};",
+        schema::schema_hash(&sources)
+    )
+    .unwrap();
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("schema_hash.rs");
+    std::fs::write(out_path, out).unwrap();
+}
+
+fn read(path: &str) -> String {
+    let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join(path);
+    std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {path:?}: {e}"))
+}
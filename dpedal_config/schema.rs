@@ -0,0 +1,288 @@
+//! Derives `SCHEMA_HASH` from the definitions of the types sent by the web config protocol.
+//! Used by build.rs to generate the hash and by tests/schema_hash.rs to check what does and does not alter it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// The source files searched for the definitions of protocol types, relative to the crate root.
+/// Older layouts in `src/migration` are deliberately left out, since they redefine types with the same names.
+pub const SOURCES: &[&str] = &[
+    "src/lib.rs",
+    "src/state.rs",
+    "src/storage.rs",
+    "src/web_config_protocol.rs",
+];
+
+/// The types every other protocol type is reached from.
+const ROOTS: &[&str] = &["Request", "Response"];
+
+/// Hashes the definitions of every struct, enum and const reachable from `Request` and `Response` in `sources`.
+///
+/// Only tokens that can affect how a value is serialized are hashed, so comments, formatting,
+/// attributes other than `#[serde(...)]`, `#[cfg(...)]` and `#[cfg_attr(...)]`, visibility, impls and unrelated items can all change without altering the hash.
+/// `cfg` attributes are kept because builds with different features can otherwise hash the same while disagreeing on which fields and variants exist.
+pub fn schema_hash(sources: &[&str]) -> u32 {
+    let mut definitions = HashMap::new();
+    for source in sources {
+        for (name, tokens) in top_level_definitions(&tokenize(source)) {
+            if definitions.insert(name.clone(), tokens).is_some() {
+                panic!(
+                    "`{name}` is defined more than once, the schema hash can not tell which one is sent"
+                );
+            }
+        }
+    }
+
+    let mut schema = String::new();
+    let mut queue: VecDeque<&str> = ROOTS.iter().copied().collect();
+    let mut visited: HashSet<&str> = queue.iter().copied().collect();
+    while let Some(name) = queue.pop_front() {
+        let Some(tokens) = definitions.get(name) else {
+            panic!("`{name}` is not defined in any of {SOURCES:?}");
+        };
+        for token in tokens {
+            schema.push_str(token);
+            schema.push(' ');
+            if let Some((referenced, _)) = definitions.get_key_value(token.as_str())
+                && visited.insert(referenced)
+            {
+                queue.push_back(referenced);
+            }
+        }
+        schema.push('\n');
+    }
+    fnv1a(&schema)
+}
+
+/// Splits Rust source into tokens, dropping comments and whitespace.
+/// String and char literals are kept whole so that e.g. a `//` or `{` inside them is not mistaken for syntax.
+fn tokenize(source: &str) -> Vec<String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            let mut depth = 0;
+            while i < chars.len() {
+                if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                    depth += 1;
+                    i += 2;
+                } else if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+            continue;
+        } else if c == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i += 1;
+        } else if c == '\'' {
+            if chars.get(i + 1) == Some(&'\\') {
+                // An escaped char literal, e.g. '\n' or '\u{7f}'
+                i += 2;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += 1;
+                }
+                i += 1;
+            } else if chars.get(i + 2) == Some(&'\'') {
+                i += 3;
+            } else {
+                // A lifetime
+                i += 1;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+            }
+        } else if is_ident_char(c) {
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            let ident: String = chars[start..i].iter().collect();
+            if matches!(ident.as_str(), "r" | "br") && matches!(chars.get(i), Some('"') | Some('#'))
+            {
+                // A raw string, which ends at a quote followed by as many hashes as it started with.
+                let hashes = chars[i..].iter().take_while(|c| **c == '#').count();
+                i += hashes + 1;
+                while i < chars.len()
+                    && !(chars[i] == '"'
+                        && chars[i + 1..].iter().take_while(|c| **c == '#').count() >= hashes)
+                {
+                    i += 1;
+                }
+                i += hashes + 1;
+            }
+        } else {
+            i += 1;
+        }
+        tokens.push(chars[start..i.min(chars.len())].iter().collect());
+    }
+    tokens
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Returns the name and wire relevant tokens of every struct, enum and const defined outside of any block.
+fn top_level_definitions(tokens: &[String]) -> Vec<(String, Vec<String>)> {
+    let mut definitions = vec![];
+    let mut depth = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].as_str() {
+            "{" | "(" | "[" => depth += 1,
+            "}" | ")" | "]" => depth -= 1,
+            // `const fn` is left to be skipped over like any other function.
+            keyword @ ("struct" | "enum" | "const") if depth == 0 && tokens[i + 1] != "fn" => {
+                let name = &tokens[i + 1];
+                let end = definition_end(tokens, i);
+                // `const _` only holds compile time assertions.
+                if name != "_" {
+                    let start = first_attribute(tokens, i);
+                    let mut definition = vec![];
+                    strip_irrelevant(&tokens[start..i], &mut definition);
+                    definition.push(keyword.to_owned());
+                    strip_irrelevant(&tokens[i + 1..end], &mut definition);
+                    definitions.push((name.clone(), definition));
+                }
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    definitions
+}
+
+/// Returns the index just past the definition starting with the keyword at `start`,
+/// which is either its closing brace or the semicolon ending e.g. a tuple struct or const.
+fn definition_end(tokens: &[String], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token.as_str() {
+            "{" | "(" | "[" => depth += 1,
+            "}" | ")" | "]" => {
+                depth -= 1;
+                if depth == 0 && token == "}" && tokens[start] != "const" {
+                    return i + 1;
+                }
+            }
+            ";" if depth == 0 => return i + 1,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// Walks back from the keyword at `keyword` over the visibility and attributes that belong to the same definition.
+fn first_attribute(tokens: &[String], keyword: usize) -> usize {
+    let mut start = keyword;
+    loop {
+        if start >= 1 && tokens[start - 1] == "pub" {
+            start -= 1;
+        } else if start >= 1 && tokens[start - 1] == ")" && tokens[..start].ends_with(&pub_crate())
+        {
+            start -= 4;
+        } else if start >= 1 && tokens[start - 1] == "]" {
+            let mut depth = 0;
+            let mut open = start - 1;
+            loop {
+                match tokens[open].as_str() {
+                    "]" => depth += 1,
+                    "[" => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                open -= 1;
+            }
+            if open >= 1 && tokens[open - 1] == "#" {
+                start = open - 1;
+            } else {
+                return start;
+            }
+        } else {
+            return start;
+        }
+    }
+}
+
+fn pub_crate() -> [String; 4] {
+    ["pub", "(", "crate", ")"].map(String::from)
+}
+
+/// Copies `tokens` into `out`, leaving out visibility and every attribute other than `#[serde(...)]`, `#[cfg(...)]` and `#[cfg_attr(...)]`.
+fn strip_irrelevant(tokens: &[String], out: &mut Vec<String>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i] == "#" && tokens.get(i + 1).map(String::as_str) == Some("[") {
+            let end = group_end(tokens, i + 1);
+            if matches!(
+                tokens.get(i + 2).map(String::as_str),
+                Some("serde" | "cfg" | "cfg_attr")
+            ) {
+                out.extend_from_slice(&tokens[i..end]);
+            }
+            i = end;
+        } else if tokens[i] == "pub" {
+            i += 1;
+            if tokens.get(i).map(String::as_str) == Some("(") {
+                i = group_end(tokens, i);
+            }
+        } else {
+            out.push(tokens[i].clone());
+            i += 1;
+        }
+    }
+}
+
+/// Returns the index just past the bracket that closes the one at `open`.
+fn group_end(tokens: &[String], open: usize) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.as_str() {
+            "{" | "(" | "[" => depth += 1,
+            "}" | ")" | "]" => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+fn fnv1a(data: &str) -> u32 {
+    let mut hash = 0x811c9dc5_u32;
+    for byte in data.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
@@ -1,5 +1,8 @@
 #![no_std]
 
+mod migration;
+pub mod state;
+pub mod storage;
 pub mod web_config_protocol;
 
 // Memory layout
@@ -16,20 +19,53 @@ use defmt::Format;
 use rkyv::{Archive, Deserialize, Serialize};
 use strum::{EnumIter, EnumString, IntoEnumIterator};
 
-const fn assert_config_fits_in_flash() {
-    // TODO: This isnt actually accurate, since the data will be serialized into rkyv format first.
-    //       Is there a way to calculate the maximum possible size in rkyv format?
-    assert!(core::mem::size_of::<Config>() <= CONFIG_SIZE);
-}
+/// The maximum possible size of a `Config` in rkyv format, reached when every `ArrayVec` and `ArrayString` is full.
+///
+/// Calculated by replaying the order in which rkyv writes out a `Config`:
+/// out of line data is written depth first in field order, followed by the archived struct or slice that points to it.
+/// Each write is padded to the alignment of its archived type.
+/// `test_max_archived_config_size` verifies this against a real fully populated config.
+pub const MAX_ARCHIVED_CONFIG_SIZE: usize = {
+    const fn write<T>(pos: usize, count: usize) -> usize {
+        pos.next_multiple_of(core::mem::align_of::<T>()) + count * core::mem::size_of::<T>()
+    }
+
+    // nickname is longer than the 8 bytes that rkyv can store inline, so it is written out of line.
+    let mut pos = write::<u8>(0, 50);
+    let mut profile = 0;
+    while profile < 2 {
+        let mut mapping = 0;
+        while mapping < MAX_MAPPINGS {
+            pos = write::<ArchivedDpedalInput>(pos, MAX_MAPPING_INPUTS);
+            pos = write::<ArchivedComputerInput>(pos, 20);
+            pos = write::<ArchivedComputerInput>(pos, MAX_RELEASE_OUTPUTS);
+            mapping += 1;
+        }
+        pos = write::<ArchivedMapping>(pos, MAX_MAPPINGS);
+        profile += 1;
+    }
+    pos = write::<ArchivedProfile>(pos, 2);
+    pos = write::<ArchivedPinRemapping>(pos, MAX_PIN_REMAPPINGS);
+    pos = write::<ArchivedAnalogInput>(pos, MAX_ANALOG_INPUTS);
+    pos = write::<ArchivedRotaryEncoder>(pos, MAX_ROTARY_ENCODERS);
+    write::<ArchivedConfig>(pos, 1)
+};
+
+/// The maximum possible size of a `Config` as stored in flash.
+pub const MAX_ENCODED_CONFIG_SIZE: usize = storage::CONFIG_HEADER_SIZE + MAX_ARCHIVED_CONFIG_SIZE;
 
 const fn assert_config_size_fits_into_writable_flash_blocks() {
     // Flash can only be written in blocks of 4096 bytes.
     assert!(CONFIG_SIZE.is_multiple_of(4096));
 }
 
-const _: () = assert_config_fits_in_flash();
+const _: () = assert!(MAX_ENCODED_CONFIG_SIZE <= CONFIG_SIZE);
 const _: () = assert_config_size_fits_into_writable_flash_blocks();
 
+/// The version of the archived layout of `Config`, stored in `Config::version`.
+/// Must be incremented whenever the archived layout of `Config` changes, see the `migration` module.
+pub const CONFIG_VERSION: u32 = 1;
+
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
 #[rkyv(derive(Debug))]
 pub struct Config {
@@ -38,68 +74,111 @@ pub struct Config {
     pub device: Device,
     pub color: u32,
     pub profiles: ArrayVec<Profile, 2>,
-    pub pin_remappings: ArrayVec<PinRemapping, 6>,
+    pub pin_remappings: ArrayVec<PinRemapping, MAX_PIN_REMAPPINGS>,
+    pub analog_inputs: ArrayVec<AnalogInput, MAX_ANALOG_INPUTS>,
+    pub rotary_encoders: ArrayVec<RotaryEncoder, MAX_ROTARY_ENCODERS>,
+    /// Outputs held for longer than this many milliseconds are released, in case an input is stuck down.
+    /// The mapping does not fire again until its inputs are released. 0 disables the limit.
+    pub max_hold_ms: u32,
+    /// How long, in milliseconds, an input that is part of a chord waits for the rest of the chord to be pressed
+    /// before firing its own mappings. 0 uses `DEFAULT_COMBO_WINDOW_MS`.
+    pub combo_window_ms: u32,
+    /// Each input of a `Trigger::Sequence` mapping must be pressed within this many milliseconds of the previous one.
+    /// 0 uses `DEFAULT_SEQUENCE_TIMEOUT_MS`.
+    pub sequence_timeout_ms: u32,
 }
 
+pub const DEFAULT_COMBO_WINDOW_MS: u32 = 50;
+pub const DEFAULT_SEQUENCE_TIMEOUT_MS: u32 = 1000;
+
 impl Default for Config {
     fn default() -> Self {
         Self {
-            version: Default::default(),
+            version: CONFIG_VERSION,
             nickname: ArrayString::from("my DPedal").unwrap(),
             device: Default::default(),
             color: 0x1790e3,
             profiles: ArrayVec::from_iter([Profile {
                 mappings: ArrayVec::from_iter([
                     Mapping {
+                        trigger: Trigger::Chord,
                         input: ArrayVec::from_iter([DpedalInput::DpadLeft]),
                         output: ArrayVec::from_iter([ComputerInput::Mouse(
                             MouseInput::ScrollLeft(10),
                         )]),
+                        release_output: ArrayVec::new(),
                     },
                     Mapping {
+                        trigger: Trigger::Chord,
                         input: ArrayVec::from_iter([DpedalInput::DpadRight]),
                         output: ArrayVec::from_iter([ComputerInput::Mouse(
                             MouseInput::ScrollRight(10),
                         )]),
+                        release_output: ArrayVec::new(),
                     },
                     Mapping {
+                        trigger: Trigger::Chord,
                         input: ArrayVec::from_iter([DpedalInput::DpadUp]),
                         output: ArrayVec::from_iter([ComputerInput::Mouse(MouseInput::ScrollUp(
                             10,
                         ))]),
+                        release_output: ArrayVec::new(),
                     },
                     Mapping {
+                        trigger: Trigger::Chord,
                         input: ArrayVec::from_iter([DpedalInput::DpadDown]),
                         output: ArrayVec::from_iter([ComputerInput::Mouse(
                             MouseInput::ScrollDown(10),
                         )]),
+                        release_output: ArrayVec::new(),
                     },
                     Mapping {
+                        trigger: Trigger::Chord,
                         input: ArrayVec::from_iter([DpedalInput::ButtonLeft]),
                         output: ArrayVec::from_iter([ComputerInput::Keyboard(
                             KeyboardInput::PageUp,
                         )]),
+                        release_output: ArrayVec::new(),
                     },
                     Mapping {
+                        trigger: Trigger::Chord,
                         input: ArrayVec::from_iter([DpedalInput::ButtonRight]),
                         output: ArrayVec::from_iter([ComputerInput::Keyboard(
                             KeyboardInput::PageDown,
                         )]),
+                        release_output: ArrayVec::new(),
                     },
                 ]),
             }]),
             pin_remappings: Default::default(),
+            analog_inputs: Default::default(),
+            rotary_encoders: Default::default(),
+            max_hold_ms: 0,
+            combo_window_ms: 0,
+            sequence_timeout_ms: 0,
         }
     }
 }
 
-#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+#[derive(
+    Archive,
+    Deserialize,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    PartialEq,
+    Default,
+    Clone,
+)]
 #[rkyv(derive(Debug))]
 pub enum Device {
     #[default]
     Dpedal,
 }
 
+/// Enough to remap every built-in input and bind every aux input.
+pub const MAX_PIN_REMAPPINGS: usize = 6 + MAX_AUX_INPUTS;
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
 #[rkyv(derive(Debug))]
 pub struct PinRemapping {
@@ -108,7 +187,116 @@ pub struct PinRemapping {
     pub pin: u32,
 }
 
+/// One for each ADC capable pin.
+pub const MAX_ANALOG_INPUTS: usize = 4;
+/// The maximum value returned by `AnalogInput::normalize`, representing full travel.
+pub const ANALOG_FULL_TRAVEL: u16 = 1000;
+
+/// A continuous input, such as an expression pedal, read via the RP2040's ADC.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+#[rkyv(derive(Debug))]
+pub struct AnalogInput {
+    /// Must be one of the ADC capable pins 26-29.
+    pub pin: u32,
+    /// Raw 12 bit ADC reading when the pedal is fully released.
+    pub min: u32,
+    /// Raw 12 bit ADC reading when the pedal is fully pressed.
+    /// May be lower than `min` for pedals wired in reverse.
+    pub max: u32,
+    /// Travel, in thousandths of full travel, that is ignored before the output starts responding.
+    pub deadzone: u32,
+    pub curve: AnalogCurve,
+    pub output: AnalogOutput,
+}
+
+impl AnalogInput {
+    /// Converts a raw ADC reading into a value between 0 and `ANALOG_FULL_TRAVEL`,
+    /// applying calibration, deadzone and curve.
+    pub fn normalize(&self, raw: u16) -> u16 {
+        let full = ANALOG_FULL_TRAVEL as u32;
+        let raw = raw as u32;
+        let (travelled, range) = if self.max >= self.min {
+            (raw.saturating_sub(self.min), self.max - self.min)
+        } else {
+            (self.min.saturating_sub(raw), self.min - self.max)
+        };
+        if range == 0 {
+            return 0;
+        }
+        let position = (travelled * full / range).min(full);
+
+        let deadzone = self.deadzone.min(full - 1);
+        if position <= deadzone {
+            return 0;
+        }
+        let position = (position - deadzone) * full / (full - deadzone);
+
+        let position = match self.curve {
+            AnalogCurve::Linear => position,
+            AnalogCurve::Exponential => position * position / full,
+            AnalogCurve::Logarithmic => {
+                let remaining = full - position;
+                full - remaining * remaining / full
+            }
+        };
+        position as u16
+    }
+}
+
+#[derive(Format, Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+#[rkyv(derive(Debug))]
+pub enum AnalogCurve {
+    #[default]
+    Linear,
+    /// Fine control near the start of travel, output rises quickly near the end.
+    Exponential,
+    /// Output rises quickly near the start of travel, fine control near the end.
+    Logarithmic,
+}
+
+#[derive(Format, Archive, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[rkyv(derive(Debug))]
+pub enum AnalogOutput {
+    /// Scroll and move outputs are scaled by pedal travel, at full travel they run at the speed given by their value.
+    /// Click outputs are held while the pedal is past half travel.
+    Mouse(MouseInput),
+}
+
+impl Default for AnalogOutput {
+    fn default() -> Self {
+        AnalogOutput::Mouse(MouseInput::ScrollDown(10))
+    }
+}
+
+pub const MAX_ROTARY_ENCODERS: usize = 2;
+
+/// A quadrature rotary encoder wired to two spare pins.
+/// Each detent fires the mappings for the matching `DpedalInput::Encoder*` input once.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+#[rkyv(derive(Debug))]
+pub struct RotaryEncoder {
+    /// Turning clockwise is detected by this pin changing before `pin_b`.
+    /// If the encoder fires in the wrong direction, swap the pins.
+    pub pin_a: u32,
+    pub pin_b: u32,
+    /// Number of quadrature steps between detents, 0 uses the common default of 4.
+    pub steps_per_detent: u32,
+}
+
+impl RotaryEncoder {
+    pub fn detent_steps(&self) -> u32 {
+        if self.steps_per_detent == 0 {
+            4
+        } else {
+            self.steps_per_detent
+        }
+    }
+}
+
 pub const MAX_MAPPINGS: usize = 20;
+/// The maximum number of inputs that can be combined into a chord or sequence.
+pub const MAX_MAPPING_INPUTS: usize = 4;
+pub const MAX_RELEASE_OUTPUTS: usize = 20;
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
 #[rkyv(derive(Debug))]
 pub struct Profile {
@@ -118,11 +306,40 @@ pub struct Profile {
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
 #[rkyv(derive(Debug))]
 pub struct Mapping {
-    pub input: ArrayVec<DpedalInput, 4>,
+    pub trigger: Trigger,
+    pub input: ArrayVec<DpedalInput, MAX_MAPPING_INPUTS>,
     pub output: ArrayVec<ComputerInput, 20>,
+    /// Tapped once the mapping's inputs are released, after `output` has been released.
+    pub release_output: ArrayVec<ComputerInput, MAX_RELEASE_OUTPUTS>,
 }
 
-#[derive(Format, Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+/// How the inputs of a `Mapping` must be pressed for it to fire.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+#[rkyv(derive(Debug))]
+pub enum Trigger {
+    /// Every input is held at the same time. The outputs are held until any of the inputs are released.
+    #[default]
+    Chord,
+    /// The inputs are pressed one after another, each within `Config::sequence_timeout_ms` of the previous.
+    /// The outputs are tapped once the last input is pressed.
+    Sequence,
+}
+
+pub const MAX_AUX_INPUTS: usize = 8;
+#[derive(
+    Format,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    PartialEq,
+    Default,
+    Clone,
+    Copy,
+    EnumIter,
+)]
 #[rkyv(derive(Debug))]
 pub enum DpedalInput {
     #[default]
@@ -132,6 +349,21 @@ pub enum DpedalInput {
     DpadRight,
     ButtonLeft,
     ButtonRight,
+    /// Aux inputs have no default pin, they are only enabled once bound to a pin via a `PinRemapping`.
+    /// This allows builders to wire in extra switches, e.g. a jack for an external footswitch.
+    Aux1,
+    Aux2,
+    Aux3,
+    Aux4,
+    Aux5,
+    Aux6,
+    Aux7,
+    Aux8,
+    /// Encoder inputs are momentary, each detent presses and then immediately releases the input.
+    Encoder1Clockwise,
+    Encoder1CounterClockwise,
+    Encoder2Clockwise,
+    Encoder2CounterClockwise,
 }
 
 impl DpedalInput {
@@ -143,6 +375,18 @@ impl DpedalInput {
             "DpadRight" => Some(Self::DpadRight),
             "ButtonLeft" => Some(Self::ButtonLeft),
             "ButtonRight" => Some(Self::ButtonRight),
+            "Aux1" => Some(Self::Aux1),
+            "Aux2" => Some(Self::Aux2),
+            "Aux3" => Some(Self::Aux3),
+            "Aux4" => Some(Self::Aux4),
+            "Aux5" => Some(Self::Aux5),
+            "Aux6" => Some(Self::Aux6),
+            "Aux7" => Some(Self::Aux7),
+            "Aux8" => Some(Self::Aux8),
+            "Encoder1Clockwise" => Some(Self::Encoder1Clockwise),
+            "Encoder1CounterClockwise" => Some(Self::Encoder1CounterClockwise),
+            "Encoder2Clockwise" => Some(Self::Encoder2Clockwise),
+            "Encoder2CounterClockwise" => Some(Self::Encoder2CounterClockwise),
             _ => None,
         }
     }
@@ -155,12 +399,69 @@ impl DpedalInput {
             "dpad-right" => Some(Self::DpadRight),
             "button-left" => Some(Self::ButtonLeft),
             "button-right" => Some(Self::ButtonRight),
+            "aux1" => Some(Self::Aux1),
+            "aux2" => Some(Self::Aux2),
+            "aux3" => Some(Self::Aux3),
+            "aux4" => Some(Self::Aux4),
+            "aux5" => Some(Self::Aux5),
+            "aux6" => Some(Self::Aux6),
+            "aux7" => Some(Self::Aux7),
+            "aux8" => Some(Self::Aux8),
+            "encoder1-clockwise" => Some(Self::Encoder1Clockwise),
+            "encoder1-counter-clockwise" => Some(Self::Encoder1CounterClockwise),
+            "encoder2-clockwise" => Some(Self::Encoder2Clockwise),
+            "encoder2-counter-clockwise" => Some(Self::Encoder2CounterClockwise),
+            _ => None,
+        }
+    }
+
+    /// The pin used by this input when no `PinRemapping` is configured for it.
+    pub fn default_pin(&self) -> Option<u32> {
+        match self {
+            Self::DpadUp => Some(26),
+            Self::DpadDown => Some(16),
+            Self::DpadLeft => Some(17),
+            Self::DpadRight => Some(22),
+            Self::ButtonLeft => Some(13),
+            Self::ButtonRight => Some(27),
+            Self::Aux1
+            | Self::Aux2
+            | Self::Aux3
+            | Self::Aux4
+            | Self::Aux5
+            | Self::Aux6
+            | Self::Aux7
+            | Self::Aux8
+            | Self::Encoder1Clockwise
+            | Self::Encoder1CounterClockwise
+            | Self::Encoder2Clockwise
+            | Self::Encoder2CounterClockwise => None,
+        }
+    }
+
+    /// The inputs fired by the rotary encoder at `index` of `Config::rotary_encoders`, as (clockwise, counter clockwise).
+    pub fn rotary_encoder(index: usize) -> Option<(Self, Self)> {
+        match index {
+            0 => Some((Self::Encoder1Clockwise, Self::Encoder1CounterClockwise)),
+            1 => Some((Self::Encoder2Clockwise, Self::Encoder2CounterClockwise)),
             _ => None,
         }
     }
 }
 
-#[derive(Format, Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+#[derive(
+    Format,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    PartialEq,
+    Default,
+    Clone,
+    Copy,
+)]
 #[rkyv(derive(Debug))]
 pub enum ComputerInput {
     #[default]
@@ -171,7 +472,18 @@ pub enum ComputerInput {
 }
 
 #[derive(
-    Format, Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy, EnumIter,
+    Format,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    PartialEq,
+    Default,
+    Clone,
+    Copy,
+    EnumIter,
 )]
 #[rkyv(derive(Debug))]
 pub enum MouseInput {
@@ -213,6 +525,8 @@ impl MouseInput {
     Archive,
     Deserialize,
     Serialize,
+    serde::Deserialize,
+    serde::Serialize,
     Debug,
     PartialEq,
     Default,
@@ -764,12 +1078,26 @@ const COMMON_KEYBOARD_INPUTS: [KeyboardInput; 93] = [
 ];
 
 #[derive(
-    Format, Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy, EnumIter,
+    Format,
+    Archive,
+    Deserialize,
+    Serialize,
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    PartialEq,
+    Default,
+    Clone,
+    Copy,
+    EnumIter,
 )]
 #[rkyv(derive(Debug))]
 pub enum DPedalControl {
     #[default]
     DoNothing,
+    /// Switches to the next profile, wrapping around to the first profile.
+    /// The active profile is remembered across power cycles.
+    NextProfile,
     // ReleaseAndSleep(u16)
     // HoldAndSleep(u16)
     // SetProfile(u8)
@@ -779,7 +1107,11 @@ impl DPedalControl {
     pub fn from_string(s: &str) -> Option<Self> {
         match s {
             "DoNothing" => Some(DPedalControl::DoNothing),
+            "NextProfile" => Some(DPedalControl::NextProfile),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,10 @@
+//! Archived layouts of `Config` used by older firmware.
+//!
+//! Whenever a change alters the archived layout of `Config`, `CONFIG_VERSION` is incremented
+//! and the types as they were before the change are frozen into a new `vN` module here.
+//! Each frozen version knows how to upgrade itself into the version that followed it,
+//! so a config stored by any older firmware can be brought up to date instead of being discarded.
+//!
+//! Appending a variant to an enum does not change how existing values are archived, so it does not need a new version.
+
+pub(crate) mod v0;
@@ -0,0 +1,142 @@
+//! The layout used before `Config::version` was enforced.
+//! Configs in this layout may contain any value in their version field.
+
+use crate::{KeyboardInput, MouseInput};
+use arrayvec::{ArrayString, ArrayVec};
+use rkyv::{Archive, Deserialize, Serialize};
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+pub struct Config {
+    pub version: u32,
+    pub nickname: ArrayString<50>,
+    pub device: Device,
+    pub color: u32,
+    pub profiles: ArrayVec<Profile, 2>,
+    pub pin_remappings: ArrayVec<PinRemapping, 6>,
+}
+
+impl Config {
+    pub fn upgrade(self) -> crate::Config {
+        crate::Config {
+            version: crate::CONFIG_VERSION,
+            nickname: self.nickname,
+            device: match self.device {
+                Device::Dpedal => crate::Device::Dpedal,
+            },
+            color: self.color,
+            profiles: self
+                .profiles
+                .into_iter()
+                .map(|profile| crate::Profile {
+                    mappings: profile
+                        .mappings
+                        .into_iter()
+                        .map(|mapping| crate::Mapping {
+                            trigger: crate::Trigger::Chord,
+                            input: mapping
+                                .input
+                                .into_iter()
+                                .map(DpedalInput::upgrade)
+                                .collect(),
+                            output: mapping
+                                .output
+                                .into_iter()
+                                .map(ComputerInput::upgrade)
+                                .collect(),
+                            release_output: ArrayVec::new(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            pin_remappings: self
+                .pin_remappings
+                .into_iter()
+                .map(|remapping| crate::PinRemapping {
+                    input: remapping.input.upgrade(),
+                    pin: remapping.pin,
+                })
+                .collect(),
+            analog_inputs: Default::default(),
+            rotary_encoders: Default::default(),
+            max_hold_ms: 0,
+            combo_window_ms: 0,
+            sequence_timeout_ms: 0,
+        }
+    }
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+pub enum Device {
+    #[default]
+    Dpedal,
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+pub struct PinRemapping {
+    pub input: DpedalInput,
+    pub pin: u32,
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+pub struct Profile {
+    pub mappings: ArrayVec<Mapping, 20>,
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone)]
+pub struct Mapping {
+    pub input: ArrayVec<DpedalInput, 4>,
+    pub output: ArrayVec<ComputerInput, 20>,
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+pub enum DpedalInput {
+    #[default]
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+    ButtonLeft,
+    ButtonRight,
+}
+
+impl DpedalInput {
+    fn upgrade(self) -> crate::DpedalInput {
+        match self {
+            DpedalInput::DpadUp => crate::DpedalInput::DpadUp,
+            DpedalInput::DpadDown => crate::DpedalInput::DpadDown,
+            DpedalInput::DpadLeft => crate::DpedalInput::DpadLeft,
+            DpedalInput::DpadRight => crate::DpedalInput::DpadRight,
+            DpedalInput::ButtonLeft => crate::DpedalInput::ButtonLeft,
+            DpedalInput::ButtonRight => crate::DpedalInput::ButtonRight,
+        }
+    }
+}
+
+/// `MouseInput` and `KeyboardInput` have not changed layout since this version, so they are shared rather than frozen.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+pub enum ComputerInput {
+    #[default]
+    None,
+    Mouse(MouseInput),
+    Keyboard(KeyboardInput),
+    Control(DPedalControl),
+}
+
+impl ComputerInput {
+    fn upgrade(self) -> crate::ComputerInput {
+        match self {
+            ComputerInput::None => crate::ComputerInput::None,
+            ComputerInput::Mouse(mouse) => crate::ComputerInput::Mouse(mouse),
+            ComputerInput::Keyboard(keyboard) => crate::ComputerInput::Keyboard(keyboard),
+            ComputerInput::Control(DPedalControl::DoNothing) => {
+                crate::ComputerInput::Control(crate::DPedalControl::DoNothing)
+            }
+        }
+    }
+}
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Default, Clone, Copy)]
+pub enum DPedalControl {
+    #[default]
+    DoNothing,
+}
@@ -0,0 +1,85 @@
+//! Runtime state that is remembered across power cycles, stored in flash separately from the config.
+//!
+//! The state region is a log of fixed size records, each write appends a record and the valid record with the highest sequence is the current state.
+//! Once a sector is full, writing moves on to the next sector which is erased first,
+//! so a sector is only erased once for every `STATE_RECORDS_PER_SECTOR` writes and the current state is never erased.
+
+use crate::storage::{CONFIG_REGION_SIZE, crc32};
+use crate::{CONFIG_OFFSET, RP2040_FLASH_SIZE};
+
+pub const STATE_OFFSET: usize = CONFIG_OFFSET + CONFIG_REGION_SIZE;
+pub const STATE_SECTOR_SIZE: usize = 4096;
+pub const STATE_SECTOR_COUNT: usize = 2;
+pub const STATE_RECORD_SIZE: usize = 8;
+pub const STATE_RECORDS_PER_SECTOR: usize = STATE_SECTOR_SIZE / STATE_RECORD_SIZE;
+pub const STATE_RECORD_COUNT: usize = STATE_SECTOR_COUNT * STATE_RECORDS_PER_SECTOR;
+
+const _: () = assert!(STATE_OFFSET + STATE_SECTOR_COUNT * STATE_SECTOR_SIZE <= RP2040_FLASH_SIZE);
+
+#[derive(defmt::Format, Debug, PartialEq, Default, Clone, Copy)]
+pub struct RuntimeState {
+    /// Index into `Config::profiles`.
+    /// May be out of range if the config changed since it was stored, in which case the first profile is used.
+    pub active_profile: u8,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StateRecord {
+    pub sequence: u32,
+    pub state: RuntimeState,
+}
+
+impl StateRecord {
+    /// Layout is the big endian sequence, the active profile, a reserved byte and then a checksum of the preceding bytes.
+    pub fn to_bytes(&self) -> [u8; STATE_RECORD_SIZE] {
+        let mut bytes = [0; STATE_RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[4] = self.state.active_profile;
+        let check = crc32(&bytes[..6]) as u16;
+        bytes[6..8].copy_from_slice(&check.to_be_bytes());
+        bytes
+    }
+
+    /// Returns `None` for erased, partially written or otherwise corrupt records.
+    pub fn from_bytes(bytes: &[u8; STATE_RECORD_SIZE]) -> Option<Self> {
+        let check = u16::from_be_bytes([bytes[6], bytes[7]]);
+        if bytes.iter().all(|x| *x == 0xFF) || check != crc32(&bytes[..6]) as u16 {
+            return None;
+        }
+        Some(StateRecord {
+            sequence: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            state: RuntimeState {
+                active_profile: bytes[4],
+            },
+        })
+    }
+}
+
+/// Offset of the record from the start of flash.
+pub const fn state_record_offset(index: usize) -> usize {
+    STATE_OFFSET + index * STATE_RECORD_SIZE
+}
+
+/// Finds the index of the current record, `read` is called with the index of every record in the state region.
+pub fn find_latest_record(
+    mut read: impl FnMut(usize) -> [u8; STATE_RECORD_SIZE],
+) -> Option<(usize, StateRecord)> {
+    let mut latest: Option<(usize, StateRecord)> = None;
+    for index in 0..STATE_RECORD_COUNT {
+        if let Some(record) = StateRecord::from_bytes(&read(index))
+            && latest.is_none_or(|(_, latest)| record.sequence > latest.sequence)
+        {
+            latest = Some((index, record));
+        }
+    }
+    latest
+}
+
+/// Where the record following `latest` should be written.
+/// If the index is the first record of a sector, that sector must be erased before writing.
+pub fn next_record_index(latest: Option<usize>) -> usize {
+    match latest {
+        Some(index) => (index + 1) % STATE_RECORD_COUNT,
+        None => 0,
+    }
+}
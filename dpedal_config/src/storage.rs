@@ -0,0 +1,235 @@
+use crate::migration::v0;
+use crate::{
+    ArchivedConfig, CONFIG_OFFSET, CONFIG_SIZE, CONFIG_VERSION, Config, RP2040_FLASH_SIZE,
+};
+use core::mem::MaybeUninit;
+use rkyv::Deserialize as RkyvDeserialize;
+use rkyv::rancor::{Failure, Panic, Strategy};
+use rkyv::ser::allocator::SubAllocator;
+use rkyv::ser::writer::Buffer;
+use serde::{Deserialize, Serialize};
+
+/// Identifies the start of a config stored in flash, "DPCF".
+pub const CONFIG_MAGIC: u32 = u32::from_be_bytes(*b"DPCF");
+/// The version of the header format itself, separate from `CONFIG_VERSION` which versions the rkyv layout.
+pub const CONFIG_HEADER_VERSION: u32 = 1;
+/// Size of the header that precedes the rkyv encoded config in flash.
+/// Keeps the config that follows it 4 byte aligned.
+pub const CONFIG_HEADER_SIZE: usize = 20;
+
+/// Number of `CONFIG_SIZE` slots that configs are written to in turn.
+///
+/// Each write goes to the slot after the newest valid config, so a write interrupted by power loss leaves the previous config intact,
+/// and erases are spread evenly over the sectors of every slot.
+pub const CONFIG_SLOT_COUNT: usize = 4;
+/// Size of all the config slots combined, starting at `CONFIG_OFFSET`.
+pub const CONFIG_REGION_SIZE: usize = CONFIG_SLOT_COUNT * CONFIG_SIZE;
+
+const _: () = assert!(CONFIG_OFFSET + CONFIG_REGION_SIZE <= RP2040_FLASH_SIZE);
+
+/// Offset of the config slot from the start of flash.
+pub const fn config_slot_offset(slot: usize) -> usize {
+    CONFIG_OFFSET + slot * CONFIG_SIZE
+}
+
+/// The header stored at the start of the config region in flash, all fields are stored big endian.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ConfigHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// Length of the rkyv encoded config following the header.
+    pub length: u32,
+    /// CRC32 of the rkyv encoded config following the header.
+    pub crc: u32,
+    /// Incremented on every write, the valid slot with the highest sequence holds the current config.
+    pub sequence: u32,
+}
+
+impl ConfigHeader {
+    pub fn new(config_bytes: &[u8], sequence: u32) -> Self {
+        ConfigHeader {
+            magic: CONFIG_MAGIC,
+            version: CONFIG_HEADER_VERSION,
+            length: config_bytes.len() as u32,
+            crc: crc32(config_bytes),
+            sequence,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONFIG_HEADER_SIZE] {
+        let mut bytes = [0; CONFIG_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.crc.to_be_bytes());
+        bytes[16..20].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; CONFIG_HEADER_SIZE]) -> Self {
+        let field = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        ConfigHeader {
+            magic: field(0),
+            version: field(4),
+            length: field(8),
+            crc: field(12),
+            sequence: field(16),
+        }
+    }
+}
+
+/// Why no config could be read from the config region in flash.
+#[derive(defmt::Format, Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum StoredConfigError {
+    /// Nothing has been written to the config region, it is still erased.
+    Empty,
+    /// The header is invalid or the config does not match its checksum.
+    Corrupt,
+}
+
+/// A config read out of a config slot in flash.
+pub struct StoredConfig<'a> {
+    /// The rkyv encoded config.
+    pub bytes: &'a [u8],
+    /// Offset of `bytes` from the start of the slot.
+    pub offset: usize,
+    /// Stored without a header by older firmware, should be rewritten with a header.
+    pub legacy: bool,
+    pub sequence: u32,
+}
+
+/// Finds the rkyv encoded config in a config slot, verifying its header and checksum.
+///
+/// Configs written by older firmware are preceded by only a big endian length instead of a header,
+/// these are still read from the first slot but can only be validated by rkyv.
+pub fn read_stored_config(
+    slot: usize,
+    region: &[u8],
+) -> Result<StoredConfig<'_>, StoredConfigError> {
+    let Some((header, rest)) = region.split_first_chunk::<CONFIG_HEADER_SIZE>() else {
+        return Err(StoredConfigError::Corrupt);
+    };
+    // Flash is erased to 0xFF, while `dpedal_flash --erase-config` writes zeroes.
+    if header.iter().all(|x| *x == 0xFF) || header.iter().all(|x| *x == 0) {
+        return Err(StoredConfigError::Empty);
+    }
+
+    let header = ConfigHeader::from_bytes(header);
+    if header.magic != CONFIG_MAGIC {
+        if slot != 0 {
+            return Err(StoredConfigError::Corrupt);
+        }
+        let length = u32::from_be_bytes(region[..4].try_into().unwrap()) as usize;
+        return match region[4..].get(..length) {
+            Some(bytes) => Ok(StoredConfig {
+                bytes,
+                offset: 4,
+                legacy: true,
+                sequence: 0,
+            }),
+            None => Err(StoredConfigError::Corrupt),
+        };
+    }
+    if header.version != CONFIG_HEADER_VERSION {
+        return Err(StoredConfigError::Corrupt);
+    }
+    let bytes = rest
+        .get(..header.length as usize)
+        .ok_or(StoredConfigError::Corrupt)?;
+    if crc32(bytes) != header.crc {
+        return Err(StoredConfigError::Corrupt);
+    }
+    Ok(StoredConfig {
+        bytes,
+        offset: CONFIG_HEADER_SIZE,
+        legacy: false,
+        sequence: header.sequence,
+    })
+}
+
+/// CRC-32/ISO-HDLC, as used by zip and ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB88320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !bytes.iter().fold(!0, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConfigError {
+    /// The bytes are not a config in any known layout.
+    Invalid,
+    /// The config does not fit in the provided buffer.
+    TooLarge,
+}
+
+/// Accesses config bytes in the current layout without copying them.
+/// Fails if the bytes are invalid or were stored in an older layout, use `decode_config` to upgrade those.
+///
+/// The bytes must be aligned to at least 4 bytes.
+pub fn access_config(bytes: &[u8]) -> Result<&ArchivedConfig, ConfigError> {
+    let archived = rkyv::api::low::access::<ArchivedConfig, Failure>(bytes)
+        .map_err(|_| ConfigError::Invalid)?;
+    if archived.version != CONFIG_VERSION {
+        return Err(ConfigError::Invalid);
+    }
+    Ok(archived)
+}
+
+/// Decodes config bytes stored in any known layout, upgrading older layouts to the current `Config`.
+///
+/// The bytes must be aligned to at least 4 bytes.
+pub fn decode_config(bytes: &[u8]) -> Result<Config, ConfigError> {
+    if let Ok(archived) = access_config(bytes) {
+        return rkyv::api::low::deserialize::<Config, Failure>(archived)
+            .map_err(|_| ConfigError::Invalid);
+    }
+
+    let archived = rkyv::api::low::access::<v0::ArchivedConfig, Failure>(bytes)
+        .map_err(|_| ConfigError::Invalid)?;
+    let config = rkyv::api::low::deserialize::<v0::Config, Failure>(archived)
+        .map_err(|_| ConfigError::Invalid)?;
+    Ok(config.upgrade())
+}
+
+/// Converts a value read from an `ArchivedConfig` back into its native type, e.g. a `DpedalInput` or `ComputerInput`.
+/// Only intended for small values, anything containing an `ArrayVec` should be accessed in place instead.
+pub fn from_archived<T>(archived: &impl RkyvDeserialize<T, Strategy<(), Panic>>) -> T {
+    let Ok(value) = rkyv::api::low::deserialize::<T, Panic>(archived);
+    value
+}
+
+/// Encodes the config into the start of `buffer` in the current layout, returning the number of bytes written.
+///
+/// Does not require an allocator so it can be used by the firmware.
+/// The buffer must be aligned to at least 4 bytes.
+pub fn encode_config(config: &Config, buffer: &mut [u8]) -> Result<usize, ConfigError> {
+    // Scratch space used by rkyv to hold the resolvers of each element while serializing an ArrayVec.
+    let mut scratch = [MaybeUninit::<u8>::uninit(); 512];
+    let bytes = rkyv::api::low::to_bytes_in_with_alloc::<_, _, Failure>(
+        config,
+        Buffer::from(buffer),
+        SubAllocator::new(&mut scratch),
+    )
+    .map_err(|_| ConfigError::TooLarge)?;
+    Ok(bytes.len())
+}
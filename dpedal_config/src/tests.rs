@@ -0,0 +1,232 @@
+use super::*;
+use crate::migration::v0;
+use crate::state::{
+    RuntimeState, STATE_RECORD_COUNT, STATE_RECORD_SIZE, STATE_RECORDS_PER_SECTOR, StateRecord,
+    find_latest_record, next_record_index,
+};
+use crate::storage::{
+    CONFIG_HEADER_SIZE, ConfigError, ConfigHeader, StoredConfigError, access_config, crc32,
+    decode_config, encode_config, from_archived, read_stored_config,
+};
+use rkyv::rancor::Failure;
+
+#[test]
+fn test_analog_input_normalize() {
+    let mut input = AnalogInput {
+        pin: 28,
+        min: 100,
+        max: 4000,
+        deadzone: 0,
+        curve: AnalogCurve::Linear,
+        output: Default::default(),
+    };
+    assert_eq!(input.normalize(0), 0);
+    assert_eq!(input.normalize(2050), 500);
+    assert_eq!(input.normalize(4095), ANALOG_FULL_TRAVEL);
+
+    input.curve = AnalogCurve::Exponential;
+    assert_eq!(input.normalize(2050), 250);
+    input.curve = AnalogCurve::Logarithmic;
+    assert_eq!(input.normalize(2050), 750);
+
+    input.curve = AnalogCurve::Linear;
+    input.deadzone = 500;
+    assert_eq!(input.normalize(2050), 0);
+    assert_eq!(input.normalize(3025), 500);
+
+    // reversed pedal
+    input.deadzone = 0;
+    input.min = 4000;
+    input.max = 100;
+    assert_eq!(input.normalize(4095), 0);
+    assert_eq!(input.normalize(100), ANALOG_FULL_TRAVEL);
+}
+
+#[test]
+fn test_decode_config_upgrades_v0() {
+    use crate::{
+        ComputerInput, DPedalControl, DpedalInput, KeyboardInput, Mapping, PinRemapping, Profile,
+        Trigger,
+    };
+    use arrayvec::{ArrayString, ArrayVec};
+
+    let v0_config = v0::Config {
+        version: 0,
+        nickname: ArrayString::from("old pedal").unwrap(),
+        device: v0::Device::Dpedal,
+        color: 0xFF0000,
+        profiles: ArrayVec::from_iter([v0::Profile {
+            mappings: ArrayVec::from_iter([v0::Mapping {
+                input: ArrayVec::from_iter([v0::DpedalInput::ButtonLeft, v0::DpedalInput::DpadUp]),
+                output: ArrayVec::from_iter([
+                    v0::ComputerInput::Keyboard(KeyboardInput::PageUp),
+                    v0::ComputerInput::Control(v0::DPedalControl::DoNothing),
+                ]),
+            }]),
+        }]),
+        pin_remappings: ArrayVec::from_iter([v0::PinRemapping {
+            input: v0::DpedalInput::DpadDown,
+            pin: 5,
+        }]),
+    };
+    let bytes = rkyv::to_bytes::<Failure>(&v0_config).unwrap();
+
+    assert_eq!(access_config(&bytes).err(), Some(ConfigError::Invalid));
+    assert_eq!(
+        decode_config(&bytes),
+        Ok(Config {
+            version: CONFIG_VERSION,
+            nickname: ArrayString::from("old pedal").unwrap(),
+            device: crate::Device::Dpedal,
+            color: 0xFF0000,
+            profiles: ArrayVec::from_iter([Profile {
+                mappings: ArrayVec::from_iter([Mapping {
+                    trigger: Trigger::Chord,
+                    input: ArrayVec::from_iter([DpedalInput::ButtonLeft, DpedalInput::DpadUp]),
+                    output: ArrayVec::from_iter([
+                        ComputerInput::Keyboard(KeyboardInput::PageUp),
+                        ComputerInput::Control(DPedalControl::DoNothing),
+                    ]),
+                    release_output: ArrayVec::new(),
+                }]),
+            }]),
+            pin_remappings: ArrayVec::from_iter([PinRemapping {
+                input: DpedalInput::DpadDown,
+                pin: 5,
+            }]),
+            analog_inputs: Default::default(),
+            rotary_encoders: Default::default(),
+            max_hold_ms: 0,
+            combo_window_ms: 0,
+            sequence_timeout_ms: 0,
+        })
+    );
+}
+
+#[test]
+fn test_encode_config_round_trip() {
+    let config = Config::default();
+    let mut buffer = rkyv::util::Align([0u8; crate::CONFIG_SIZE]);
+    let len = encode_config(&config, &mut *buffer).unwrap();
+
+    let archived = access_config(&buffer[..len]).unwrap();
+    let mapping = &config.profiles[0].mappings[0];
+    assert_eq!(
+        from_archived::<crate::DpedalInput>(&archived.profiles[0].mappings[0].input[0]),
+        mapping.input[0]
+    );
+    assert_eq!(
+        from_archived::<crate::ComputerInput>(&archived.profiles[0].mappings[0].output[0]),
+        mapping.output[0]
+    );
+    assert_eq!(decode_config(&buffer[..len]), Ok(config));
+}
+
+#[test]
+fn test_max_archived_config_size() {
+    use crate::{
+        AnalogInput, ComputerInput, DpedalInput, KeyboardInput, MAX_ANALOG_INPUTS,
+        MAX_ARCHIVED_CONFIG_SIZE, MAX_MAPPING_INPUTS, MAX_MAPPINGS, MAX_PIN_REMAPPINGS,
+        MAX_RELEASE_OUTPUTS, MAX_ROTARY_ENCODERS, Mapping, PinRemapping, Profile, RotaryEncoder,
+        Trigger,
+    };
+    use arrayvec::{ArrayString, ArrayVec};
+
+    let mapping = Mapping {
+        trigger: Trigger::Sequence,
+        input: ArrayVec::from([DpedalInput::DpadUp; MAX_MAPPING_INPUTS]),
+        output: ArrayVec::from([ComputerInput::Keyboard(KeyboardInput::A); 20]),
+        release_output: ArrayVec::from(
+            [ComputerInput::Keyboard(KeyboardInput::B); MAX_RELEASE_OUTPUTS],
+        ),
+    };
+    let config = Config {
+        nickname: ArrayString::from(&"a".repeat(50)).unwrap(),
+        profiles: ArrayVec::from([(); 2].map(|_| Profile {
+            mappings: ArrayVec::from([(); MAX_MAPPINGS].map(|_| mapping.clone())),
+        })),
+        pin_remappings: ArrayVec::from([(); MAX_PIN_REMAPPINGS].map(|_| PinRemapping::default())),
+        analog_inputs: ArrayVec::from([(); MAX_ANALOG_INPUTS].map(|_| AnalogInput::default())),
+        rotary_encoders: ArrayVec::from(
+            [(); MAX_ROTARY_ENCODERS].map(|_| RotaryEncoder::default()),
+        ),
+        ..Config::default()
+    };
+    assert!(config.profiles.is_full());
+
+    let mut buffer = rkyv::util::Align([0u8; crate::CONFIG_SIZE]);
+    let len = encode_config(&config, &mut *buffer).unwrap();
+    assert_eq!(len, MAX_ARCHIVED_CONFIG_SIZE);
+    assert_eq!(decode_config(&buffer[..len]), Ok(config));
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+}
+
+#[test]
+fn test_read_stored_config() {
+    let config = [1, 2, 3, 4, 5];
+    let mut region = [0xFF; 64];
+    assert!(matches!(
+        read_stored_config(0, &region),
+        Err(StoredConfigError::Empty)
+    ));
+
+    region[..CONFIG_HEADER_SIZE].copy_from_slice(&ConfigHeader::new(&config, 7).to_bytes());
+    region[CONFIG_HEADER_SIZE..CONFIG_HEADER_SIZE + config.len()].copy_from_slice(&config);
+    let stored = read_stored_config(1, &region).unwrap();
+    assert_eq!(stored.bytes, config);
+    assert_eq!(stored.offset, CONFIG_HEADER_SIZE);
+    assert_eq!(stored.sequence, 7);
+    assert!(!stored.legacy);
+
+    region[CONFIG_HEADER_SIZE] = 0;
+    assert!(matches!(
+        read_stored_config(1, &region),
+        Err(StoredConfigError::Corrupt)
+    ));
+
+    let mut legacy_region = [0; 64];
+    legacy_region[..4].copy_from_slice(&(config.len() as u32).to_be_bytes());
+    legacy_region[4..4 + config.len()].copy_from_slice(&config);
+    let stored = read_stored_config(0, &legacy_region).unwrap();
+    assert_eq!(stored.bytes, config);
+    assert!(stored.legacy);
+    assert!(matches!(
+        read_stored_config(1, &legacy_region),
+        Err(StoredConfigError::Corrupt)
+    ));
+}
+
+#[test]
+fn test_state_record_log() {
+    let mut flash = [[0xFF; STATE_RECORD_SIZE]; STATE_RECORD_COUNT];
+    assert_eq!(find_latest_record(|i| flash[i]), None);
+
+    let mut latest = None;
+    for sequence in 0..STATE_RECORD_COUNT as u32 + 10 {
+        let index = next_record_index(latest.map(|(index, _)| index));
+        if index.is_multiple_of(STATE_RECORDS_PER_SECTOR) {
+            flash[index..index + STATE_RECORDS_PER_SECTOR].fill([0xFF; STATE_RECORD_SIZE]);
+        }
+        let record = StateRecord {
+            sequence,
+            state: RuntimeState {
+                active_profile: sequence as u8 % 2,
+            },
+        };
+        flash[index] = record.to_bytes();
+
+        latest = find_latest_record(|i| flash[i]);
+        assert_eq!(latest, Some((index, record)));
+    }
+
+    // A partially written record is ignored in favour of the previous record.
+    let (index, record) = latest.unwrap();
+    let next = next_record_index(Some(index));
+    flash[next][0] = 0;
+    assert_eq!(find_latest_record(|i| flash[i]), Some((index, record)));
+}
@@ -1,18 +1,189 @@
-use crate::CONFIG_SIZE;
-use arrayvec::ArrayVec;
+use crate::storage::StoredConfigError;
+use crate::{ComputerInput, Device, DpedalInput};
+use arrayvec::{ArrayString, ArrayVec};
 use serde::{Deserialize, Serialize};
 
+/// Incremented whenever `Request` or `Response` change in a way that older firmware or configurators cannot understand.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+include!(concat!(env!("OUT_DIR"), "/schema_hash.rs"));
+
+/// The size of the buffers that each side accumulates a COBS encoded message into.
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// The maximum number of config bytes carried by a single message.
+/// Configs are much larger than `MAX_MESSAGE_SIZE`, so they are transferred as a series of chunks.
+/// Leaves plenty of room within `MAX_MESSAGE_SIZE` for the postcard and COBS encoding overhead.
+pub const CONFIG_CHUNK_SIZE: usize = 512;
+
+/// A config applied by `Request::SetConfigVolatile` is reverted if no request is received for this long,
+/// so that it does not outlive a configurator that was closed without reverting or committing it.
+/// Configurators send `Request::KeepAlive` to keep trying the config for longer.
+pub const VOLATILE_CONFIG_TIMEOUT_MS: u64 = 5000;
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[expect(clippy::large_enum_variant)]
 pub enum Request {
+    /// Loads the stored config and returns its length, its bytes are then read with `GetConfigChunk`.
+    /// While a config applied by `SetConfigVolatile` is being tried, this is still the config stored in flash rather than the one in use.
     GetConfig,
-    SetConfig(ArrayVec<u8, CONFIG_SIZE>),
+    /// Returns up to `CONFIG_CHUNK_SIZE` bytes of the config loaded by the last `GetConfig`, starting at `offset`.
+    GetConfigChunk {
+        offset: u32,
+    },
+    /// Uploads part of a new config.
+    /// Chunks must be sent in order, each starting where the previous one ended, an offset of 0 starts a new upload.
+    SetConfigChunk {
+        offset: u32,
+        bytes: ArrayVec<u8, CONFIG_CHUNK_SIZE>,
+    },
+    /// Validates the uploaded config, which must be exactly `len` bytes long, then stores and applies it.
+    SetConfig {
+        len: u32,
+    },
+    /// Describes the firmware.
+    /// Firmware that predates this request responds with `Response::ProtocolError`.
+    GetDeviceInfo,
+    /// Must be sent before any other request, the device refuses everything but `GetDeviceInfo` and `Handshake` until a compatible handshake has been made.
+    /// The device responds with its own `Handshake` so that the configurator can also check compatibility.
+    /// The position of this variant must never change so that every version of the firmware and configurator can decode it.
+    Handshake(Handshake),
+    /// Starts streaming `Response::InputEvent`s, until `Unsubscribe` is sent or the device is disconnected.
+    Subscribe,
+    Unsubscribe,
+    /// Like `SetConfig` but the config is only applied, not stored, so that it can be tried out without wearing the flash.
+    /// The config is reverted by `RevertConfig`, a new `Handshake`, the device being disconnected
+    /// or no request being received within `VOLATILE_CONFIG_TIMEOUT_MS`.
+    SetConfigVolatile {
+        len: u32,
+    },
+    /// Stores the config applied by `SetConfigVolatile`.
+    CommitConfig,
+    /// Reapplies the stored config, discarding the config applied by `SetConfigVolatile`, does nothing if there is none.
+    RevertConfig,
+    /// Does nothing, but like every request keeps a config applied by `SetConfigVolatile` from timing out.
+    KeepAlive,
+    /// Returns the index into `Config::profiles` of the active profile.
+    GetActiveProfile,
+    /// Switches to the profile at this index into `Config::profiles` once every output of the current profile has been released.
+    /// Subscribers are sent `InputEvent::ProfileChanged` once the switch has happened.
+    SetActiveProfile(u8),
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[expect(clippy::large_enum_variant)]
 pub enum Response {
-    GetConfig(Result<ArrayVec<u8, CONFIG_SIZE>, ()>),
-    SetConfig,
+    GetConfig(Result<u32, StoredConfigError>),
+    GetConfigChunk(ArrayVec<u8, CONFIG_CHUNK_SIZE>),
+    SetConfigChunk,
+    SetConfig(Result<(), SetConfigError>),
     ProtocolError,
+    GetDeviceInfo(DeviceInfo),
+    /// The position of this variant must never change so that every version of the firmware and configurator can decode it.
+    Handshake(Handshake),
+    Subscribe,
+    Unsubscribe,
+    /// Sent without a request while subscribed, possibly between a request and its response.
+    InputEvent(InputEvent),
+    SetConfigVolatile(Result<(), SetConfigError>),
+    CommitConfig(Result<(), SetConfigError>),
+    RevertConfig,
+    KeepAlive,
+    GetActiveProfile(u8),
+    SetActiveProfile(Result<(), SetActiveProfileError>),
+}
+
+/// Identifies the protocol spoken by each side of the connection.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub schema_hash: u32,
+}
+
+impl Handshake {
+    /// The protocol spoken by this build.
+    pub const CURRENT: Handshake = Handshake {
+        protocol_version: PROTOCOL_VERSION,
+        schema_hash: SCHEMA_HASH,
+    };
+
+    /// Returns true if the other side of the connection speaks the same protocol as this build.
+    pub fn is_compatible(&self) -> bool {
+        *self == Self::CURRENT
+    }
+}
+
+/// Why the device rejected a config sent by `Request::SetConfig` or `Request::SetConfigVolatile`, the previous config remains in use.
+#[derive(defmt::Format, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum SetConfigError {
+    /// The config does not fit in a config slot.
+    TooLarge,
+    /// The bytes are not a valid config.
+    FailedValidation,
+    /// The config is valid but could not be written to flash.
+    FlashWriteError,
+    /// The config is in an older layout, it must be upgraded to the layout of the device's firmware before sending it.
+    VersionMismatch,
+    /// `Request::CommitConfig` was sent but no config applied by `Request::SetConfigVolatile` is in use, it may have been reverted.
+    NothingToCommit,
 }
+
+/// Why the device refused to switch profile for `Request::SetActiveProfile`.
+#[derive(defmt::Format, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum SetActiveProfileError {
+    /// The config has no profile at that index.
+    DoesNotExist,
+}
+
+/// Describes the firmware running on the device and what it supports.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct DeviceInfo {
+    /// The version of `dpedal_firmware`.
+    pub firmware_version: ArrayString<16>,
+    pub build_profile: BuildProfile,
+    /// The git commit the firmware was built from, empty if it was not built from a git checkout.
+    pub git_hash: ArrayString<40>,
+    pub device: Device,
+    /// The `PROTOCOL_VERSION` of the firmware.
+    pub protocol_version: u32,
+    /// Size of the device's flash in bytes.
+    pub flash_size: u32,
+    /// The kinds of `ComputerInput` that the firmware can output.
+    pub supported_outputs: ArrayVec<OutputKind, 8>,
+}
+
+#[derive(defmt::Format, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum BuildProfile {
+    Debug,
+    Release,
+}
+
+/// The kind of a `ComputerInput`, ignoring which key, button or control it is.
+#[derive(defmt::Format, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum OutputKind {
+    Mouse,
+    Keyboard,
+    Control,
+}
+
+/// Something that happened while processing the pedal's inputs, streamed to subscribed configurators.
+/// Events are dropped rather than delaying the inputs if the configurator does not keep up.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub enum InputEvent {
+    /// The switches that are currently closed, sent whenever any of them changes.
+    /// This is before debouncing, so that a bouncy switch can be spotted.
+    Switches(ArrayVec<DpedalInput, MAX_SWITCHES>),
+    /// A momentary input, such as a rotary encoder detent, was tapped.
+    Tapped(DpedalInput),
+    /// The mapping at index `mapping` of the profile at index `profile` fired.
+    MappingTriggered { profile: u8, mapping: u8 },
+    /// An output was pressed on the computer.
+    Pressed(ComputerInput),
+    /// An output was released on the computer.
+    Released(ComputerInput),
+    /// The profile at this index became active, either from a mapping or `Request::SetActiveProfile`.
+    ProfileChanged(u8),
+}
+
+/// Enough for every `DpedalInput` to be closed at once.
+pub const MAX_SWITCHES: usize = 32;
@@ -0,0 +1,111 @@
+#[path = "../schema.rs"]
+mod schema;
+
+use dpedal_config::web_config_protocol::SCHEMA_HASH;
+
+fn read_sources() -> Vec<String> {
+    schema::SOURCES
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(format!("{}/{path}", env!("CARGO_MANIFEST_DIR"))).unwrap()
+        })
+        .collect()
+}
+
+/// Hashes the sources after applying `edit` to the source file at `path`.
+fn hash_with_edit(path: &str, edit: impl Fn(&str) -> String) -> u32 {
+    let mut sources = read_sources();
+    let index = schema::SOURCES.iter().position(|x| *x == path).unwrap();
+    sources[index] = edit(&sources[index]);
+    assert_ne!(sources, read_sources(), "the edit must change the source");
+    schema::schema_hash(&sources.iter().map(String::as_str).collect::<Vec<_>>())
+}
+
+#[test]
+fn test_schema_hash_matches_build() {
+    let sources = read_sources();
+    assert_eq!(
+        schema::schema_hash(&sources.iter().map(String::as_str).collect::<Vec<_>>()),
+        SCHEMA_HASH
+    );
+}
+
+#[test]
+fn test_schema_hash_ignores_wire_neutral_edits() {
+    let protocol = "src/web_config_protocol.rs";
+    let comment = hash_with_edit(protocol, |source| {
+        source.replacen(
+            "pub enum Request {",
+            "/// Reworded docs.\npub enum Request { // A comment containing \"quotes\", a // and a {\n/* a block comment } */",
+            1,
+        )
+    });
+    assert_eq!(comment, SCHEMA_HASH);
+
+    let formatting = hash_with_edit(protocol, |source| source.replace("    ", "\t"));
+    assert_eq!(formatting, SCHEMA_HASH);
+
+    let derive = hash_with_edit(protocol, |source| {
+        source.replace("#[derive(", "#[derive(Hash, ")
+    });
+    assert_eq!(derive, SCHEMA_HASH);
+
+    let additions = hash_with_edit(protocol, |source| {
+        format!(
+            "{source}\nimpl Request {{\n    const SLASHES: &str = \"// }}\";\n}}\n\npub const UNRELATED: char = '{{';\n"
+        )
+    });
+    assert_eq!(additions, SCHEMA_HASH);
+}
+
+#[test]
+fn test_schema_hash_detects_nested_changes() {
+    let variant = hash_with_edit("src/lib.rs", |source| {
+        source.replacen(
+            "pub enum MouseInput {",
+            "pub enum MouseInput {\n    ClickBack,",
+            1,
+        )
+    });
+    assert_ne!(variant, SCHEMA_HASH);
+
+    let capacity = hash_with_edit("src/web_config_protocol.rs", |source| {
+        source.replacen(
+            "pub const MAX_SWITCHES: usize = 32;",
+            "pub const MAX_SWITCHES: usize = 33;",
+            1,
+        )
+    });
+    assert_ne!(capacity, SCHEMA_HASH);
+
+    let error = hash_with_edit("src/storage.rs", |source| {
+        source.replacen(
+            "pub enum StoredConfigError {",
+            "pub enum StoredConfigError {\n    Unknown,",
+            1,
+        )
+    });
+    assert_ne!(error, SCHEMA_HASH);
+}
+
+#[test]
+fn test_schema_hash_detects_cfg() {
+    let protocol = "src/web_config_protocol.rs";
+    let variant = hash_with_edit(protocol, |source| {
+        source.replacen(
+            "pub enum Request {",
+            "pub enum Request {\n    #[cfg(feature = \"x\")]",
+            1,
+        )
+    });
+    assert_ne!(variant, SCHEMA_HASH);
+
+    let field = hash_with_edit(protocol, |source| {
+        source.replacen(
+            "pub struct Handshake {",
+            "pub struct Handshake {\n    #[cfg_attr(feature = \"x\", serde(skip))]",
+            1,
+        )
+    });
+    assert_ne!(field, SCHEMA_HASH);
+}
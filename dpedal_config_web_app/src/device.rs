@@ -1,11 +1,19 @@
-use dpedal_config::web_config_protocol::{Request, Response};
+use dpedal_config::web_config_protocol::{InputEvent, MAX_MESSAGE_SIZE, Request, Response};
+use futures::StreamExt;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::lock::Mutex;
 use postcard::accumulator::CobsAccumulator;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use webusb_web::{OpenUsbDevice, Usb, UsbDeviceFilter};
 
 pub struct Device {
-    usb: OpenUsbDevice,
-    lock: Mutex<()>,
+    usb: Rc<OpenUsbDevice>,
+    /// Responses in the order that they were received.
+    responses: Mutex<UnboundedReceiver<Result<Response, String>>>,
+    /// Set while a request is waiting for its response, so that errors are only reported to a request that can fail because of them.
+    awaiting_response: Rc<Cell<bool>>,
+    input_events: RefCell<Option<UnboundedReceiver<InputEvent>>>,
 }
 
 impl Device {
@@ -32,39 +40,100 @@ impl Device {
             .await
             .map_err(|e| e.msg().to_string())?;
 
+        let usb = Rc::new(open_usb);
+        let (responses_sender, responses) = mpsc::unbounded();
+        let (input_events_sender, input_events) = mpsc::unbounded();
+        let awaiting_response = Rc::new(Cell::new(false));
+        wasm_bindgen_futures::spawn_local(receive_messages(
+            usb.clone(),
+            responses_sender,
+            input_events_sender,
+            awaiting_response.clone(),
+        ));
+
         Ok(Device {
-            usb: open_usb,
-            lock: Mutex::new(()),
+            usb,
+            responses: Mutex::new(responses),
+            awaiting_response,
+            input_events: RefCell::new(Some(input_events)),
         })
     }
 
     pub async fn send_request(&self, request: &Request) -> Result<Response, String> {
         // Need to hold the lock for the duration of request/response pair
-        let _lock = self.lock.lock().await;
+        let mut responses = self.responses.lock().await;
 
         let request_bytes = postcard::to_stdvec_cobs(request).unwrap(); // TODO: when can this fail?
-        self.usb
-            .transfer_out(1, &request_bytes)
+        self.awaiting_response.set(true);
+        if let Err(e) = self.usb.transfer_out(1, &request_bytes).await {
+            self.awaiting_response.set(false);
+            return Err(format!("Failed to send request to device: {e}"));
+        }
+
+        let response = responses
+            .next()
             .await
-            .map_err(|e| format!("Failed to send request to device: {e}"))?;
+            .unwrap_or_else(|| Err("Device is no longer connected".into()));
+        self.awaiting_response.set(false);
+        response
+    }
+
+    /// Returns the events sent by the device after `Request::Subscribe`, can only be taken once.
+    pub fn take_input_events(&self) -> Option<UnboundedReceiver<InputEvent>> {
+        self.input_events.borrow_mut().take()
+    }
+}
 
-        let mut cobs_buf: CobsAccumulator<1024> = CobsAccumulator::new();
-        loop {
-            let out = self
-                .usb
-                .transfer_in(1, 64)
-                .await
-                .map_err(|e| format!("Failed to receive response from device: {e}"))?;
-            match cobs_buf.feed::<Response>(&out) {
-                postcard::accumulator::FeedResult::Consumed => {}
-                postcard::accumulator::FeedResult::OverFull(_items) => {
-                    return Err("Device sent response > 1024 bytes".into());
+/// Reads every message sent by the device, separating input events, which can arrive at any time, from responses.
+async fn receive_messages(
+    usb: Rc<OpenUsbDevice>,
+    responses: UnboundedSender<Result<Response, String>>,
+    input_events: UnboundedSender<InputEvent>,
+    awaiting_response: Rc<Cell<bool>>,
+) {
+    // An error is only handed to the request waiting for a response, and at most once.
+    // Otherwise it is logged, since queueing it would fail the next request in place of that request's own response.
+    let fail = |error: String| {
+        if awaiting_response.replace(false) {
+            let _ = responses.unbounded_send(Err(error));
+        } else {
+            log::error!("{error}");
+        }
+    };
+    let mut cobs_buf: CobsAccumulator<MAX_MESSAGE_SIZE> = CobsAccumulator::new();
+    loop {
+        let out = match usb.transfer_in(1, 64).await {
+            Ok(out) => out,
+            Err(e) => {
+                fail(format!("Failed to receive response from device: {e}"));
+                return;
+            }
+        };
+        let mut window = &out[..];
+        while !window.is_empty() {
+            window = match cobs_buf.feed::<Response>(window) {
+                postcard::accumulator::FeedResult::Consumed => break,
+                postcard::accumulator::FeedResult::OverFull(remaining) => {
+                    fail(format!("Device sent response > {MAX_MESSAGE_SIZE} bytes"));
+                    remaining
                 }
-                postcard::accumulator::FeedResult::DeserError(_items) => {
-                    return Err("Device sent response that could not be parsed.".into());
+                postcard::accumulator::FeedResult::DeserError(remaining) => {
+                    fail("Device sent response that could not be parsed.".into());
+                    remaining
                 }
-                postcard::accumulator::FeedResult::Success { data, .. } => return Ok(data),
-            }
+                postcard::accumulator::FeedResult::Success {
+                    data: Response::InputEvent(event),
+                    remaining,
+                } => {
+                    let _ = input_events.unbounded_send(event);
+                    remaining
+                }
+                postcard::accumulator::FeedResult::Success { data, remaining } => {
+                    awaiting_response.set(false);
+                    let _ = responses.unbounded_send(Ok(data));
+                    remaining
+                }
+            };
         }
     }
 }
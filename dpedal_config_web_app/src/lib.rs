@@ -1,18 +1,38 @@
 use arrayvec::ArrayString;
 use arrayvec::ArrayVec;
+use dpedal_config::CONFIG_SIZE;
 use dpedal_config::ComputerInput;
 use dpedal_config::Config;
 use dpedal_config::DPedalControl;
 use dpedal_config::DpedalInput;
 use dpedal_config::KeyboardInput;
+use dpedal_config::MAX_MAPPINGS;
 use dpedal_config::Mapping;
 use dpedal_config::MouseInput;
 use dpedal_config::Profile;
+use dpedal_config::Trigger;
+use dpedal_config::storage::CONFIG_HEADER_SIZE;
+use dpedal_config::storage::StoredConfigError;
+use dpedal_config::web_config_protocol::BuildProfile;
+use dpedal_config::web_config_protocol::CONFIG_CHUNK_SIZE;
+use dpedal_config::web_config_protocol::DeviceInfo;
+use dpedal_config::web_config_protocol::Handshake;
+use dpedal_config::web_config_protocol::InputEvent;
+use dpedal_config::web_config_protocol::OutputKind;
+use dpedal_config::web_config_protocol::PROTOCOL_VERSION;
 use dpedal_config::web_config_protocol::Request;
 use dpedal_config::web_config_protocol::Response;
+use dpedal_config::web_config_protocol::SCHEMA_HASH;
+use dpedal_config::web_config_protocol::SetActiveProfileError;
+use dpedal_config::web_config_protocol::SetConfigError;
+use dpedal_config::web_config_protocol::VOLATILE_CONFIG_TIMEOUT_MS;
 use element_iterator::ElementChildIterator;
+use futures::StreamExt;
+use futures::channel::mpsc::UnboundedReceiver;
 use log::Level;
 use rkyv::rancor::Error;
+use rkyv::util::AlignedVec;
+use std::cell::RefCell;
 use std::rc::Rc;
 use std::str::FromStr;
 use strum::IntoEnumIterator;
@@ -56,13 +76,25 @@ async fn open_device() {
         }
     };
 
+    if let Err(err) = handshake(&device).await {
+        set_error(&document, &err);
+        return;
+    }
+    let device_info = match request_device_info(&device).await {
+        Ok(device_info) => device_info,
+        Err(err) => {
+            set_error(&document, &format!("Failed to request device info: {err}"));
+            return;
+        }
+    };
+
     let config = match request_get_config(&device).await {
         Ok(x) => x,
         Err(err) => {
             log::error!("Failed to request config from device {err}");
             web_sys::window()
                 .unwrap()
-                .alert_with_message("Config on the device is not present, outdated, or corrupt. Default config will be restored.")
+                .alert_with_message(&format!("{err}. Default config will be restored."))
                 .unwrap();
             Default::default()
         }
@@ -80,15 +112,19 @@ async fn open_device() {
             <label>Nickname: </label>
             <input type="text" id="device_name" style="font-size:2em;">
             <input type="color" id="device_color">
+            <label>Active profile: </label>
+            <select id="active-profile" style="font-size:2em;"></select>
 
-            <table id="input-output-table">
-                <tr>
-                    <th>Input</th>
-                    <th>Output</th>
-                </tr>
-            </table>
+            <div id="profiles"></div>
             <button id="save">Save</button>
+            <button id="try">Try</button>
+            <button id="revert">Revert</button>
             <span id="save-result" style="font-size:1.5em;"></span>
+            <p id="try-status"></p>
+            <p id="config-budget"></p>
+            <p id="device-info"></p>
+            <p id="live-switches"></p>
+            <p id="live-outputs"></p>
             "#,
     );
 
@@ -105,21 +141,55 @@ async fn open_device() {
     let color = color.dyn_ref::<HtmlInputElement>().unwrap();
     color.set_value(&format!("#{:x}", config.color));
 
-    if let Some(profile) = config.profiles.first() {
-        gen_for_profile(&document, profile);
+    for (i, profile) in config.profiles.iter().enumerate() {
+        gen_for_profile(&document, i, profile, &device_info.supported_outputs);
+    }
+    set_config_budget(&document, &config);
+    set_device_info(&document, &device_info);
+
+    if let Some(input_events) = device.take_input_events() {
+        match device.send_request(&Request::Subscribe).await {
+            Ok(Response::Subscribe) => {
+                wasm_bindgen_futures::spawn_local(show_input_events(input_events))
+            }
+            Ok(response) => log::error!("Unexpected dpedal response {response:?}"),
+            Err(err) => log::error!("Failed to subscribe to input events {err}"),
+        }
     }
     log::info!("device config {:#?}", config);
 
     let device = Rc::new(device);
-    set_button_on_click(
-        &document,
-        "save",
-        Box::new(move || {
-            let device = device.clone();
-            let config = config.clone();
-            wasm_bindgen_futures::spawn_local(write_config_task(device, config));
-        }) as Box<dyn FnMut()>,
-    );
+    setup_active_profile(&document, &device, config.profiles.len()).await;
+    let tried = Rc::new(RefCell::new(None));
+    for (id, mode) in [("save", WriteMode::Save), ("try", WriteMode::Try)] {
+        let device = device.clone();
+        let config = config.clone();
+        let tried = tried.clone();
+        set_button_on_click(
+            &document,
+            id,
+            Box::new(move || {
+                let device = device.clone();
+                let config = config.clone();
+                let tried = tried.clone();
+                wasm_bindgen_futures::spawn_local(write_config_task(device, config, tried, mode));
+            }) as Box<dyn FnMut()>,
+        );
+    }
+    {
+        let device = device.clone();
+        let tried = tried.clone();
+        set_button_on_click(
+            &document,
+            "revert",
+            Box::new(move || {
+                let device = device.clone();
+                let tried = tried.clone();
+                wasm_bindgen_futures::spawn_local(revert_config_task(device, tried));
+            }) as Box<dyn FnMut()>,
+        );
+    }
+    wasm_bindgen_futures::spawn_local(keep_alive(device, tried));
 
     log::info!("Setup complete");
 }
@@ -135,17 +205,81 @@ pub async fn sleep(millis: i32) {
     JsFuture::from(promise).await.unwrap();
 }
 
-async fn write_config_task(device: Rc<Device>, config: Config) {
+/// Displays the pedal's active profile and switches the pedal to whichever profile is selected.
+async fn setup_active_profile(document: &Document, device: &Rc<Device>, profile_count: usize) {
+    let select = document.get_element_by_id("active-profile").unwrap();
+    let select = select.dyn_ref::<HtmlSelectElement>().unwrap();
+    let options: String = (0..profile_count)
+        .map(|i| format!("<option value=\"{i}\">{}</option>", i + 1))
+        .collect();
+    select.set_inner_html(&options);
+
+    match device.send_request(&Request::GetActiveProfile).await {
+        Ok(Response::GetActiveProfile(profile)) => select.set_value(&profile.to_string()),
+        Ok(response) => log::error!("Unexpected dpedal response {response:?}"),
+        Err(err) => log::error!("Failed to request active profile {err}"),
+    }
+
+    let device = device.clone();
+    let select_clone = select.clone();
+    set_onchange(
+        select,
+        Box::new(move || {
+            if let Ok(profile) = select_clone.value().parse() {
+                wasm_bindgen_futures::spawn_local(set_active_profile_task(device.clone(), profile));
+            }
+        }) as Box<dyn FnMut()>,
+    );
+}
+
+async fn set_active_profile_task(device: Rc<Device>, profile: u8) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    match device
+        .send_request(&Request::SetActiveProfile(profile))
+        .await
+    {
+        Ok(Response::SetActiveProfile(Ok(()))) => {}
+        Ok(Response::SetActiveProfile(Err(SetActiveProfileError::DoesNotExist))) => set_error(
+            &document,
+            &format!("Profile {} does not exist on the device", profile + 1),
+        ),
+        Ok(response) => set_error(
+            &document,
+            &format!("Unexpected dpedal response {response:?}"),
+        ),
+        Err(err) => set_error(&document, &err),
+    }
+}
+
+/// The config that was most recently tried, if it has not since been saved or reverted.
+type TriedConfig = Rc<RefCell<Option<Config>>>;
+
+/// How `write_config` applies the config to the device.
+#[derive(Clone, Copy)]
+enum WriteMode {
+    /// Store the config in flash.
+    Save,
+    /// Only apply the config, so that it can be tried out without wearing the flash.
+    Try,
+}
+
+async fn write_config_task(
+    device: Rc<Device>,
+    config: Config,
+    tried: TriedConfig,
+    mode: WriteMode,
+) {
     let document = web_sys::window().unwrap().document().unwrap();
     let save_result = document.get_element_by_id("save-result").unwrap();
     let save_result = save_result.dyn_ref::<HtmlElement>().unwrap();
     save_result.set_inner_html("🌀");
 
-    if let Err(err) = write_config(&document, device, config).await {
+    if let Err(err) = write_config(&document, device, config, &tried, mode).await {
         save_result.set_inner_html("❌");
         set_error(&document, &err);
         return;
     }
+    set_try_status(&document, &tried);
 
     save_result.set_inner_html("✅");
     sleep(500).await;
@@ -160,27 +294,57 @@ async fn write_config_task(device: Rc<Device>, config: Config) {
     save_result.style().set_property("opacity", "100%").unwrap();
 }
 
+async fn revert_config_task(device: Rc<Device>, tried: TriedConfig) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    match device.send_request(&Request::RevertConfig).await {
+        Ok(Response::RevertConfig) => *tried.borrow_mut() = None,
+        Ok(response) => set_error(
+            &document,
+            &format!("Unexpected dpedal response {response:?}"),
+        ),
+        Err(err) => set_error(&document, &err),
+    }
+    set_try_status(&document, &tried);
+}
+
+/// Keeps the device from reverting the config being tried.
+async fn keep_alive(device: Rc<Device>, tried: TriedConfig) {
+    loop {
+        sleep(VOLATILE_CONFIG_TIMEOUT_MS as i32 / 5).await;
+        if tried.borrow().is_none() {
+            continue;
+        }
+        match device.send_request(&Request::KeepAlive).await {
+            Ok(Response::KeepAlive) => {}
+            Ok(response) => log::error!("Unexpected dpedal response {response:?}"),
+            Err(err) => {
+                log::error!("Failed to keep the tried config alive {err}");
+                return;
+            }
+        }
+    }
+}
+
+fn set_try_status(document: &Document, tried: &TriedConfig) {
+    let try_status = document.get_element_by_id("try-status").unwrap();
+    try_status.set_inner_html(if tried.borrow().is_some() {
+        "Trying config, it will be reverted unless saved"
+    } else {
+        ""
+    });
+}
+
 async fn write_config(
     document: &Document,
     device: Rc<Device>,
     mut config: Config,
+    tried: &TriedConfig,
+    mode: WriteMode,
 ) -> Result<(), String> {
-    let table = document.get_element_by_id("input-output-table").unwrap();
-
-    let mut mappings = ArrayVec::new();
-
-    // Iterate over rows, skipping the header
-    for row in ElementChildIterator::new(&table).skip(1) {
-        let mut cells = ElementChildIterator::new(&row);
-        let input_cell = ElementChildIterator::new(&cells.next().unwrap())
-            .next()
-            .unwrap();
-        let output = parse_output_cell(&cells.next().unwrap());
-
-        let input = input_cell.inner_html();
-        let input = ArrayVec::from_iter([DpedalInput::from_string(&input)
-            .ok_or_else(|| format!("{input} is not a valid input"))?]);
-        mappings.push(Mapping { input, output });
+    // Every profile is saved, not just the one that is active, otherwise the others would be lost.
+    for (i, profile) in config.profiles.iter_mut().enumerate() {
+        let table = document.get_element_by_id(&format!("profile-{i}")).unwrap();
+        profile.mappings = parse_profile_table(&table)?;
     }
 
     let name = document.get_element_by_id("device_name").unwrap();
@@ -196,19 +360,74 @@ async fn write_config(
     let color = color.dyn_ref::<HtmlInputElement>().unwrap();
     config.color = u32::from_str_radix(color.value().strip_prefix("#").unwrap(), 16).unwrap();
 
-    config.profiles = ArrayVec::from_iter([Profile { mappings }]);
-
-    let config_bytes =
-        ArrayVec::from_iter(rkyv::to_bytes::<Error>(&config).unwrap().iter().cloned());
-    device
-        .send_request(&Request::SetConfig(config_bytes))
-        .await?;
+    let config_bytes = rkyv::to_bytes::<Error>(&config).unwrap();
+    if config_bytes.len() > CONFIG_SIZE - CONFIG_HEADER_SIZE {
+        return Err(format!(
+            "Config uses {} bytes but only {} bytes are available",
+            config_bytes.len() + CONFIG_HEADER_SIZE,
+            CONFIG_SIZE
+        ));
+    }
+    // Saving the config that is being tried only needs to store it, rather than upload it again.
+    let unchanged_since_tried = tried.borrow().as_ref() == Some(&config);
+    match mode {
+        WriteMode::Save if unchanged_since_tried => {
+            if !request_commit_config(&device).await? {
+                request_set_config(&device, &config_bytes, false).await?
+            }
+        }
+        WriteMode::Save => request_set_config(&device, &config_bytes, false).await?,
+        WriteMode::Try => request_set_config(&device, &config_bytes, true).await?,
+    }
+    set_config_budget(document, &config);
     log::info!("config written {:#?}", config);
+    *tried.borrow_mut() = match mode {
+        WriteMode::Save => None,
+        WriteMode::Try => Some(config),
+    };
 
     Ok(())
 }
 
-fn parse_output_cell(output_cell: &Element) -> ArrayVec<ComputerInput, 20> {
+fn parse_profile_table(table: &Element) -> Result<ArrayVec<Mapping, MAX_MAPPINGS>, String> {
+    let mut mappings = ArrayVec::new();
+
+    // Iterate over rows, skipping the header
+    for row in ElementChildIterator::new(table).skip(1) {
+        let mut cells = ElementChildIterator::new(&row);
+        let input_cell = ElementChildIterator::new(&cells.next().unwrap())
+            .next()
+            .unwrap();
+        let trigger = parse_trigger_cell(&cells.next().unwrap());
+        let output = parse_output_cell(&cells.next().unwrap());
+        let release_output = parse_output_cell(&cells.next().unwrap());
+
+        let input = input_cell
+            .inner_html()
+            .split(INPUT_SEPARATORS)
+            .map(|x| x.trim())
+            .map(|x| DpedalInput::from_string(x).ok_or_else(|| format!("{x} is not a valid input")))
+            .collect::<Result<_, _>>()?;
+        mappings.push(Mapping {
+            trigger,
+            input,
+            output,
+            release_output,
+        });
+    }
+    Ok(mappings)
+}
+
+fn parse_trigger_cell(trigger_cell: &Element) -> Trigger {
+    let select = ElementChildIterator::new(trigger_cell).next().unwrap();
+    let select = select.dyn_ref::<HtmlSelectElement>().unwrap();
+    match select.value().as_str() {
+        "Sequence" => Trigger::Sequence,
+        _ => Trigger::Chord,
+    }
+}
+
+fn parse_output_cell<const CAP: usize>(output_cell: &Element) -> ArrayVec<ComputerInput, CAP> {
     ElementChildIterator::new(output_cell)
         .flat_map(|span| parse_output_span(&span))
         .collect()
@@ -246,28 +465,235 @@ fn parse_output_span(output_span: &Element) -> Option<ComputerInput> {
     }
 }
 
+/// Checks that the device speaks the same protocol as this configurator, returning an explanation of what to update if it does not.
+async fn handshake(device: &Device) -> Result<(), String> {
+    let firmware_outdated =
+        "The device firmware is outdated and cannot be configured until it is updated".to_owned();
+    match device
+        .send_request(&Request::Handshake(Handshake::CURRENT))
+        .await?
+    {
+        Response::Handshake(handshake) if handshake.is_compatible() => Ok(()),
+        Response::Handshake(handshake) => {
+            if handshake.protocol_version < PROTOCOL_VERSION {
+                Err(firmware_outdated)
+            } else if handshake.protocol_version > PROTOCOL_VERSION {
+                Err("The device firmware is newer than this configurator, reload the page to update it".to_owned())
+            } else {
+                Err(format!(
+                    "The device firmware was built from a different version of dpedal (protocol schema {:08x}, expected {SCHEMA_HASH:08x}), update the firmware or reload the page so that they match",
+                    handshake.schema_hash
+                ))
+            }
+        }
+        // Firmware from before the handshake was introduced can not decode it.
+        Response::ProtocolError => Err(firmware_outdated),
+        response => Err(format!("Unexpected dpedal response {response:?}")),
+    }
+}
+
+async fn request_device_info(device: &Device) -> Result<DeviceInfo, String> {
+    match device.send_request(&Request::GetDeviceInfo).await? {
+        Response::GetDeviceInfo(device_info) => Ok(device_info),
+        response => Err(format!("Unexpected dpedal response {response:?}")),
+    }
+}
+
 async fn request_get_config(device: &Device) -> Result<Config, String> {
-    let response = device.send_request(&Request::GetConfig).await?;
-    match response {
-        Response::GetConfig(config_bytes) => {
-            // TODO: Is Align required in some circumstances?
-            rkyv::from_bytes::<Config, rkyv::rancor::Error>(
-                &config_bytes.map_err(|e| format!("{e:?}"))?,
-            )
-            .map_err(|e| format!("{e:?}"))
+    let len = match device.send_request(&Request::GetConfig).await? {
+        Response::GetConfig(len) => len.map_err(|e| match e {
+            StoredConfigError::Empty => "No config has been stored on the device".to_owned(),
+            StoredConfigError::Corrupt => "Config on the device is corrupt".to_owned(),
+        })?,
+        response => return Err(format!("Unexpected dpedal response {response:?}")),
+    };
+
+    // The bytes must be aligned for rkyv to access them.
+    let mut config_bytes = AlignedVec::<16>::new();
+    while config_bytes.len() < len as usize {
+        let offset = config_bytes.len() as u32;
+        match device
+            .send_request(&Request::GetConfigChunk { offset })
+            .await?
+        {
+            Response::GetConfigChunk(chunk) if !chunk.is_empty() => {
+                config_bytes.extend_from_slice(&chunk)
+            }
+            response => return Err(format!("Unexpected dpedal response {response:?}")),
         }
-        Response::SetConfig => panic!("Unexpected dpedal response"),
-        Response::ProtocolError => panic!("dpedal protocol error"),
     }
+
+    dpedal_config::storage::decode_config(&config_bytes).map_err(|_| {
+        "Config on the device is from a newer firmware or could not be read".to_owned()
+    })
 }
 
-fn gen_for_profile(document: &Document, profile: &Profile) {
-    let table = document.get_element_by_id("input-output-table").unwrap();
+/// Uploads the config in chunks, the device only applies it once every chunk has been received.
+/// A volatile config is applied without being stored.
+async fn request_set_config(
+    device: &Device,
+    config_bytes: &[u8],
+    volatile: bool,
+) -> Result<(), String> {
+    for (i, chunk) in config_bytes.chunks(CONFIG_CHUNK_SIZE).enumerate() {
+        let request = Request::SetConfigChunk {
+            offset: (i * CONFIG_CHUNK_SIZE) as u32,
+            bytes: ArrayVec::try_from(chunk).unwrap(),
+        };
+        match device.send_request(&request).await? {
+            Response::SetConfigChunk => {}
+            response => return Err(format!("Unexpected dpedal response {response:?}")),
+        }
+    }
+
+    let len = config_bytes.len() as u32;
+    let request = if volatile {
+        Request::SetConfigVolatile { len }
+    } else {
+        Request::SetConfig { len }
+    };
+    match device.send_request(&request).await? {
+        Response::SetConfig(result) | Response::SetConfigVolatile(result) => {
+            result.map_err(set_config_error_message)
+        }
+        response => Err(format!("Unexpected dpedal response {response:?}")),
+    }
+}
+
+/// Stores the config that is being tried, returns false if the device had already reverted it.
+async fn request_commit_config(device: &Device) -> Result<bool, String> {
+    match device.send_request(&Request::CommitConfig).await? {
+        Response::CommitConfig(Ok(())) => Ok(true),
+        Response::CommitConfig(Err(SetConfigError::NothingToCommit)) => Ok(false),
+        Response::CommitConfig(Err(e)) => Err(set_config_error_message(e)),
+        response => Err(format!("Unexpected dpedal response {response:?}")),
+    }
+}
 
-    for mapping in &profile.mappings {
-        let row = create_row(document, mapping);
+fn set_config_error_message(e: SetConfigError) -> String {
+    match e {
+        SetConfigError::TooLarge => "Config is too large to store on the device",
+        SetConfigError::FailedValidation => "Device rejected the config as invalid",
+        SetConfigError::FlashWriteError => "Device failed to write the config to flash",
+        SetConfigError::VersionMismatch => {
+            "Device firmware expects a different config version, try updating the firmware"
+        }
+        SetConfigError::NothingToCommit => "Device is no longer trying the config",
+    }
+    .to_owned()
+}
+
+fn gen_for_profile(
+    document: &Document,
+    profile_index: usize,
+    profile: &Profile,
+    supported_outputs: &SupportedOutputs,
+) {
+    let profiles = document.get_element_by_id("profiles").unwrap();
+
+    let heading = document.create_element("h2").unwrap();
+    heading.set_inner_html(&format!("Profile {}", profile_index + 1));
+    profiles.append_child(&heading).unwrap();
+
+    let table = document.create_element("table").unwrap();
+    table.set_id(&format!("profile-{profile_index}"));
+    table.set_inner_html(
+        r#"
+            <tr>
+                <th>Input</th>
+                <th>Trigger</th>
+                <th>Output</th>
+                <th>Release output</th>
+            </tr>
+            "#,
+    );
+    for (i, mapping) in profile.mappings.iter().enumerate() {
+        let row = create_row(document, mapping, supported_outputs);
+        row.set_id(&format!("mapping-{profile_index}-{i}"));
         table.append_child(&row).unwrap();
     }
+    profiles.append_child(&table).unwrap();
+}
+
+/// Displays what the pedal is doing as it happens, so that e.g. builders can check that every switch works.
+async fn show_input_events(mut input_events: UnboundedReceiver<InputEvent>) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let switches = document.get_element_by_id("live-switches").unwrap();
+    let switches = switches.dyn_ref::<HtmlElement>().unwrap();
+    let outputs = document.get_element_by_id("live-outputs").unwrap();
+    let outputs = outputs.dyn_ref::<HtmlElement>().unwrap();
+    let mut held_outputs: Vec<ComputerInput> = vec![];
+
+    while let Some(event) = input_events.next().await {
+        match event {
+            InputEvent::Switches(closed) => switches.set_inner_text(&format!(
+                "Closed switches: {}",
+                closed
+                    .iter()
+                    .map(|x| format!("{x:?}"))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )),
+            InputEvent::Tapped(input) => switches.set_inner_text(&format!("Tapped: {input:?}")),
+            InputEvent::MappingTriggered { profile, mapping } => {
+                if let Some(row) =
+                    document.get_element_by_id(&format!("mapping-{profile}-{mapping}"))
+                {
+                    wasm_bindgen_futures::spawn_local(highlight_row(row));
+                }
+            }
+            InputEvent::ProfileChanged(profile) => {
+                let select = document.get_element_by_id("active-profile").unwrap();
+                let select = select.dyn_ref::<HtmlSelectElement>().unwrap();
+                select.set_value(&profile.to_string());
+            }
+            InputEvent::Pressed(output) => {
+                held_outputs.push(output);
+                outputs.set_inner_text(&format!("Held outputs: {held_outputs:?}"));
+            }
+            InputEvent::Released(output) => {
+                if let Some(i) = held_outputs.iter().position(|held| *held == output) {
+                    held_outputs.remove(i);
+                }
+                outputs.set_inner_text(&format!("Held outputs: {held_outputs:?}"));
+            }
+        }
+    }
+}
+
+/// Briefly highlights the row of a mapping that fired.
+async fn highlight_row(row: Element) {
+    let row = row.dyn_ref::<HtmlElement>().unwrap();
+    row.style()
+        .set_property("background-color", "yellow")
+        .unwrap();
+    sleep(300).await;
+    row.style().remove_property("background-color").unwrap();
+}
+
+/// Displays how much of the device's config flash the config uses.
+fn set_config_budget(document: &Document, config: &Config) {
+    let used = CONFIG_HEADER_SIZE + rkyv::to_bytes::<Error>(config).unwrap().len();
+    let budget = document.get_element_by_id("config-budget").unwrap();
+    budget.set_inner_html(&format!("Config uses {used} of {CONFIG_SIZE} bytes"));
+}
+
+fn set_device_info(document: &Document, device_info: &DeviceInfo) {
+    let build_profile = match device_info.build_profile {
+        BuildProfile::Debug => " (debug build)",
+        BuildProfile::Release => "",
+    };
+    let git_hash = match device_info.git_hash.get(..8) {
+        Some(short_hash) => format!(" {short_hash}"),
+        None => String::new(),
+    };
+    let info = document.get_element_by_id("device-info").unwrap();
+    info.set_inner_html(&format!(
+        "{:?} firmware v{}{git_hash}{build_profile}, {} MiB flash",
+        device_info.device,
+        device_info.firmware_version,
+        device_info.flash_size / (1024 * 1024)
+    ));
 }
 
 pub fn set_error(document: &Document, error_message: &str) {
@@ -276,26 +702,43 @@ pub fn set_error(document: &Document, error_message: &str) {
     error.set_inner_text(error_message);
 }
 
-fn create_row(document: &Document, mapping: &Mapping) -> Element {
+fn create_row(
+    document: &Document,
+    mapping: &Mapping,
+    supported_outputs: &SupportedOutputs,
+) -> Element {
     let tr = document.create_element("tr").unwrap();
 
-    tr.append_child(&create_row_input(document, &mapping.input))
-        .unwrap();
-    tr.append_child(&create_row_output(document, &mapping.output))
+    let input = create_row_input(document, mapping.trigger, &mapping.input);
+    tr.append_child(&input).unwrap();
+    tr.append_child(&create_row_trigger(document, mapping.trigger, &input))
         .unwrap();
+    tr.append_child(&create_row_output(
+        document,
+        &mapping.output,
+        supported_outputs,
+    ))
+    .unwrap();
+    tr.append_child(&create_row_output(
+        document,
+        &mapping.release_output,
+        supported_outputs,
+    ))
+    .unwrap();
 
     tr
 }
 
 fn create_row_input<const CAP: usize>(
     document: &Document,
+    trigger: Trigger,
     inputs: &ArrayVec<DpedalInput, CAP>,
 ) -> Element {
     let input_value = inputs
         .iter()
         .map(|x| format!("{x:?}"))
         .collect::<Vec<String>>()
-        .join("+");
+        .join(input_separator(trigger));
     let td1 = document.create_element("td").unwrap();
 
     let input = document.create_element("p").unwrap();
@@ -305,24 +748,75 @@ fn create_row_input<const CAP: usize>(
     td1
 }
 
+/// Every separator `create_row_input` may have joined the inputs with.
+const INPUT_SEPARATORS: [char; 2] = ['+', ','];
+
+fn input_separator(trigger: Trigger) -> &'static str {
+    match trigger {
+        Trigger::Chord => "+",
+        Trigger::Sequence => ", ",
+    }
+}
+
+/// The trigger is chosen explicitly, since e.g. a sequence of one input can not be told apart from a chord by its inputs alone.
+fn create_row_trigger(document: &Document, trigger: Trigger, input_td: &Element) -> Element {
+    let td = document.create_element("td").unwrap();
+    let select = document.create_element("select").unwrap();
+    let select = select.dyn_ref::<HtmlSelectElement>().unwrap();
+    select.set_inner_html(
+        "<option value=\"Chord\">Chord</option><option value=\"Sequence\">Sequence</option>",
+    );
+    select.style().set_css_text("font-size:2em;");
+    select.set_value(&format!("{trigger:?}"));
+    td.append_child(select).unwrap();
+
+    let td_clone = td.clone();
+    let input = ElementChildIterator::new(input_td).next().unwrap();
+    let input = input.dyn_into::<HtmlElement>().unwrap();
+    set_onchange(
+        select,
+        Box::new(move || {
+            // Keep the displayed inputs joined the way the newly selected trigger presses them.
+            let trigger = parse_trigger_cell(&td_clone);
+            let inputs = input.inner_text();
+            let inputs: Vec<&str> = inputs.split(INPUT_SEPARATORS).map(|x| x.trim()).collect();
+            input.set_inner_text(&inputs.join(input_separator(trigger)));
+        }) as Box<dyn FnMut()>,
+    );
+
+    td
+}
+
 fn create_row_output<const CAP: usize>(
     document: &Document,
     outputs: &ArrayVec<ComputerInput, CAP>,
+    supported_outputs: &SupportedOutputs,
 ) -> Element {
     let td = document.create_element("td").unwrap();
     let span = document.create_element("span").unwrap();
     td.append_child(&span).unwrap();
 
     for output in outputs {
-        setup_single_output_span(&span, output);
+        setup_single_output_span(&span, output, supported_outputs);
+    }
+    // Still offer a choice of output when there are none, so that e.g. a release output can be added.
+    if outputs.is_empty() {
+        setup_single_output_span(&span, &ComputerInput::None, supported_outputs);
     }
 
     td
 }
 
+/// The kinds of output the device's firmware reported that it supports, only these are offered when editing an output.
+type SupportedOutputs = ArrayVec<OutputKind, 8>;
+
 /// Create or recreate a single output span.
 /// The output cell of the mapping table can contain many of these spans, each corresponding to a distinct output or step in a dpedal macro.
-fn setup_single_output_span(span: &Element, output: &ComputerInput) {
+fn setup_single_output_span(
+    span: &Element,
+    output: &ComputerInput,
+    supported_outputs: &SupportedOutputs,
+) {
     let document = web_sys::window().unwrap().document().unwrap();
 
     // Remove any existing children
@@ -333,24 +827,54 @@ fn setup_single_output_span(span: &Element, output: &ComputerInput) {
     // Add new children
     let select_type = document.create_element("select").unwrap();
     let select_type = select_type.dyn_ref::<HtmlSelectElement>().unwrap();
-    select_type.set_inner_html(
-        "
-<option value=\"keyboard\">⌨️</option>
-<option value=\"mouse\">🖱️</option>
-<option value=\"control\">⚙️</option>
-",
-    );
+    let mut options = "<option value=\"none\">➖</option>".to_owned();
+    for (kind, value, label) in [
+        (OutputKind::Keyboard, "keyboard", "⌨️"),
+        (OutputKind::Mouse, "mouse", "🖱️"),
+        (OutputKind::Control, "control", "⚙️"),
+    ] {
+        // Outputs that are already in the config are kept so that they are not silently lost.
+        let in_use = matches!(
+            (kind, output),
+            (OutputKind::Keyboard, ComputerInput::Keyboard(_))
+                | (OutputKind::Mouse, ComputerInput::Mouse(_))
+                | (OutputKind::Control, ComputerInput::Control(_))
+        );
+        if supported_outputs.contains(&kind) || in_use {
+            options.push_str(&format!("<option value=\"{value}\">{label}</option>"));
+        }
+    }
+    select_type.set_inner_html(&options);
     select_type.style().set_css_text("font-size:2em;");
     select_type.set_value(match output {
-        ComputerInput::None => {
-            return;
-        }
+        ComputerInput::None => "none",
         ComputerInput::Mouse(_) => "mouse",
         ComputerInput::Keyboard(_) => "keyboard",
         ComputerInput::Control(_) => "control",
     });
     span.append_child(select_type).unwrap();
 
+    let span_clone = span.clone();
+    let supported_outputs = supported_outputs.clone();
+    set_onchange(
+        select_type,
+        Box::new(move || {
+            let select_type = ElementChildIterator::new(&span_clone).next().unwrap();
+            let select_type = select_type.dyn_ref::<HtmlSelectElement>().unwrap();
+            let output = match select_type.value().as_str() {
+                "mouse" => ComputerInput::Mouse(Default::default()),
+                "keyboard" => ComputerInput::Keyboard(Default::default()),
+                "control" => ComputerInput::Control(Default::default()),
+                _ => ComputerInput::None,
+            };
+            setup_single_output_span(&span_clone, &output, &supported_outputs);
+        }) as Box<dyn FnMut()>,
+    );
+
+    if let ComputerInput::None = output {
+        return;
+    }
+
     let select_subtype = document.create_element("select").unwrap();
     let select_subtype = select_subtype.dyn_ref::<HtmlSelectElement>().unwrap();
     select_subtype.style().set_css_text("font-size:2em;");
@@ -422,22 +946,6 @@ fn setup_single_output_span(span: &Element, output: &ComputerInput) {
         }
     }
     span.append_child(&subtype_fields_span).unwrap();
-
-    let span = span.clone();
-    set_onchange(
-        select_type,
-        Box::new(move || {
-            let select_type = ElementChildIterator::new(&span).next().unwrap();
-            let select_type = select_type.dyn_ref::<HtmlSelectElement>().unwrap();
-            let output = match select_type.value().as_str() {
-                "mouse" => ComputerInput::Mouse(Default::default()),
-                "keyboard" => ComputerInput::Keyboard(Default::default()),
-                "control" => ComputerInput::Control(Default::default()),
-                _ => ComputerInput::None,
-            };
-            setup_single_output_span(&span, &output);
-        }) as Box<dyn FnMut()>,
-    );
 }
 
 fn setup_subtype_fields(span: &Element, mouse_input: &MouseInput) {
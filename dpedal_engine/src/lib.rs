@@ -0,0 +1,670 @@
+#![no_std]
+
+//! The hardware independent logic that turns DPedal inputs into computer outputs.
+//!
+//! The firmware reads the pins and feeds their state into an `Engine` along with the current time,
+//! then drains the resulting `Action`s with `Engine::next_action` and sends them over USB.
+//! Keeping this logic free of any hardware allows it to be tested on the host.
+
+use arrayvec::ArrayVec;
+use dpedal_config::storage::from_archived;
+use dpedal_config::{
+    ArchivedComputerInput, ArchivedConfig, ArchivedDPedalControl, ArchivedDpedalInput,
+    ArchivedMapping, ArchivedProfile, ArchivedTrigger, ComputerInput, DEFAULT_COMBO_WINDOW_MS,
+    DEFAULT_SEQUENCE_TIMEOUT_MS, DpedalInput, MAX_MAPPING_INPUTS, MAX_MAPPINGS,
+};
+use strum::IntoEnumIterator;
+
+pub mod report;
+
+/// Time in milliseconds since an arbitrary point, such as boot.
+pub type Millis = u64;
+
+/// Switches must be stable for this long before their new state is used.
+pub const DEBOUNCE_MS: Millis = 5;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Pressed(ComputerInput),
+    Released(ComputerInput),
+    /// The active profile changed, it should be persisted so the pedal starts in this profile next boot.
+    ProfileChanged(usize),
+    /// Release every held output, regardless of which mapping pressed it.
+    /// Emitted when the config is replaced or the profile switched, so that nothing can be left stuck down.
+    ReleaseAll,
+}
+
+pub struct Engine {
+    mapping_state: ArrayVec<MappingState, MAX_MAPPINGS>,
+    profile_index: usize,
+    /// The profile to switch to once every output of the current profile has been released.
+    pending_profile: Option<usize>,
+    /// The profile was switched without going through `pending_profile`, e.g. because the new config no longer has it,
+    /// and `Action::ProfileChanged` still needs to be emitted.
+    profile_changed_pending: bool,
+    /// The most recently read state of the pins, which may still be bouncing.
+    raw_inputs: InputState,
+    raw_inputs_changed_at: Millis,
+    /// The debounced state of the pins.
+    inputs: InputState,
+    /// Pressed inputs that are part of a chord, which are hidden from mappings until the rest of the chord is pressed
+    /// or the combo window elapses. Mappings are evaluated against `inputs` without these.
+    held_back: InputState,
+    /// When `held_back` last went from empty to non-empty.
+    held_back_since: Millis,
+    /// The most recent presses, oldest first, matched against `Trigger::Sequence` mappings.
+    sequence: ArrayVec<DpedalInput, MAX_MAPPING_INPUTS>,
+    last_pressed_at: Millis,
+    /// The last inputs of just completed sequences, which do not fire any other mappings until released.
+    sequence_ends: InputState,
+    /// A tapped input was pressed and still needs to be released.
+    tap_pending: bool,
+    release_all_pending: bool,
+    /// The profile and mapping index of mappings that fired since the last `take_triggered_mappings`.
+    triggered: ArrayVec<(usize, usize), MAX_MAPPINGS>,
+    /// The most recent time passed to `update`.
+    now: Millis,
+    /// Cached from `Config::max_hold_ms`.
+    max_hold: Option<Millis>,
+    /// Cached from `Config::combo_window_ms`.
+    combo_window: Millis,
+    /// Cached from `Config::sequence_timeout_ms`.
+    sequence_timeout: Millis,
+}
+
+impl Engine {
+    pub fn new(profile_index: usize) -> Self {
+        Engine {
+            mapping_state: ArrayVec::new(),
+            profile_index,
+            pending_profile: None,
+            profile_changed_pending: false,
+            raw_inputs: InputState::default(),
+            raw_inputs_changed_at: 0,
+            inputs: InputState::default(),
+            held_back: InputState::default(),
+            held_back_since: 0,
+            sequence: ArrayVec::new(),
+            last_pressed_at: 0,
+            sequence_ends: InputState::default(),
+            tap_pending: false,
+            release_all_pending: false,
+            triggered: ArrayVec::new(),
+            now: 0,
+            max_hold: None,
+            combo_window: DEFAULT_COMBO_WINDOW_MS as Millis,
+            sequence_timeout: DEFAULT_SEQUENCE_TIMEOUT_MS as Millis,
+        }
+    }
+
+    pub fn active_profile(&self) -> usize {
+        self.profile_index
+    }
+
+    /// Feeds in the current state of the pins.
+    /// Must be called whenever a pin changes and once `next_deadline` has passed.
+    pub fn update(&mut self, config: &ArchivedConfig, raw_inputs: InputState, now: Millis) {
+        self.now = now;
+        if raw_inputs != self.raw_inputs {
+            self.raw_inputs = raw_inputs;
+            self.raw_inputs_changed_at = now;
+        }
+
+        let mut changed = false;
+        let mut taps = InputState::default();
+        if self.inputs != self.raw_inputs && now >= self.raw_inputs_changed_at + DEBOUNCE_MS {
+            let previous = self.inputs;
+            self.inputs = self.raw_inputs;
+            for input in DpedalInput::iter() {
+                if self.inputs.is_pressed(input) && !previous.is_pressed(input) {
+                    self.record_press(config, input);
+                }
+            }
+            taps = self.hold_back_chords(config, previous);
+            changed = true;
+        }
+
+        if !self.held_back.is_empty() && now >= self.held_back_since + self.combo_window {
+            self.held_back = InputState::default();
+            changed = true;
+        }
+
+        if !taps.is_empty() {
+            // Chord inputs released before the combo window elapsed still fire their own mappings once.
+            self.evaluate(config, self.effective_inputs().union(taps));
+            self.tap_pending = true;
+        } else if changed {
+            self.evaluate(config, self.effective_inputs());
+        }
+
+        if let Some(max_hold) = self.max_hold {
+            for mapping_state in &mut self.mapping_state {
+                if let MappingState::Pressed { since } = mapping_state
+                    && now >= *since + max_hold
+                {
+                    *mapping_state = MappingState::Releasing {
+                        next: 0,
+                        reason: ReleaseReason::Interrupted,
+                    };
+                }
+            }
+        }
+    }
+
+    /// The time at which `update` must next be called, even if no pins have changed.
+    pub fn next_deadline(&self) -> Option<Millis> {
+        let debounce =
+            (self.inputs != self.raw_inputs).then_some(self.raw_inputs_changed_at + DEBOUNCE_MS);
+        let max_hold = self.max_hold.and_then(|max_hold| {
+            self.mapping_state
+                .iter()
+                .filter_map(|mapping_state| match mapping_state {
+                    MappingState::Pressed { since } => Some(since + max_hold),
+                    _ => None,
+                })
+                .min()
+        });
+        let combo_window =
+            (!self.held_back.is_empty()).then_some(self.held_back_since + self.combo_window);
+        [debounce, max_hold, combo_window]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Presses and then immediately releases `input`, so that e.g. every rotary encoder detent fires the mapping's outputs exactly once.
+    /// Tapped inputs are not debounced.
+    pub fn tap(&mut self, config: &ArchivedConfig, input: DpedalInput, now: Millis) {
+        self.now = now;
+        self.record_press(config, input);
+        let mut inputs = self.effective_inputs();
+        inputs.set_pressed(input);
+        self.evaluate(config, inputs);
+        self.tap_pending = true;
+    }
+
+    /// Switches to `profile_index` once every output of the current profile has been released.
+    pub fn set_active_profile(&mut self, profile_index: usize) {
+        if profile_index != self.profile_index {
+            self.pending_profile = Some(profile_index);
+        }
+    }
+
+    /// Returns the profile and mapping index of every mapping that fired since the last call, oldest first.
+    /// Only needed for displaying what the engine is doing, mappings that fire while the buffer is full are not recorded.
+    pub fn take_triggered_mappings(&mut self) -> ArrayVec<(usize, usize), MAX_MAPPINGS> {
+        core::mem::take(&mut self.triggered)
+    }
+
+    /// Must be called after the config is replaced.
+    /// The outputs of the previous config can no longer be released individually since its mappings are gone,
+    /// so everything is released with `Action::ReleaseAll` instead.
+    pub fn config_changed(&mut self, config: &ArchivedConfig) {
+        self.pending_profile = None;
+        self.tap_pending = false;
+        self.release_all_pending = true;
+        if self.profile_index != 0 && self.profile_index >= config.profiles.len() {
+            // The active profile was removed, so fall back to the first profile and report it so that it is persisted.
+            self.profile_index = 0;
+            self.profile_changed_pending = true;
+        }
+        self.max_hold = match config.max_hold_ms.to_native() {
+            0 => None,
+            max_hold_ms => Some(max_hold_ms as Millis),
+        };
+        self.combo_window = match config.combo_window_ms.to_native() {
+            0 => DEFAULT_COMBO_WINDOW_MS,
+            combo_window_ms => combo_window_ms,
+        } as Millis;
+        self.sequence_timeout = match config.sequence_timeout_ms.to_native() {
+            0 => DEFAULT_SEQUENCE_TIMEOUT_MS,
+            sequence_timeout_ms => sequence_timeout_ms,
+        } as Millis;
+        self.reset_mapping_state(config);
+    }
+
+    /// Returns the next action to perform.
+    /// Must be called until it returns `None` before calling any other method, otherwise presses could be skipped.
+    pub fn next_action(&mut self, config: &ArchivedConfig) -> Option<Action> {
+        loop {
+            if self.release_all_pending {
+                self.release_all_pending = false;
+                return Some(Action::ReleaseAll);
+            }
+
+            if self.profile_changed_pending {
+                self.profile_changed_pending = false;
+                return Some(Action::ProfileChanged(self.profile_index));
+            }
+
+            if let Some(profile) = active_profile(config, self.profile_index) {
+                for (mapping, mapping_state) in
+                    profile.mappings.iter().zip(self.mapping_state.iter_mut())
+                {
+                    if let Some(action) = mapping_state.advance(mapping) {
+                        return Some(action);
+                    }
+                }
+            }
+
+            if self.tap_pending {
+                self.tap_pending = false;
+                self.evaluate(config, self.effective_inputs());
+                continue;
+            }
+
+            if let Some(profile_index) = self.pending_profile {
+                let mut releasing = false;
+                for mapping_state in &mut self.mapping_state {
+                    if let MappingState::Pressed { .. } = mapping_state {
+                        *mapping_state = MappingState::Releasing {
+                            next: 0,
+                            reason: ReleaseReason::ProfileChanged,
+                        };
+                        releasing = true;
+                    }
+                }
+                if releasing {
+                    continue;
+                }
+
+                self.pending_profile = None;
+                self.profile_index = profile_index;
+                self.release_all_pending = true;
+                self.reset_mapping_state(config);
+                return Some(Action::ProfileChanged(profile_index));
+            }
+
+            return None;
+        }
+    }
+
+    /// Mappings whose inputs are already held must wait for them to be released,
+    /// otherwise the mapping that switched profile could immediately fire a mapping in the new profile.
+    fn reset_mapping_state(&mut self, config: &ArchivedConfig) {
+        self.held_back = InputState::default();
+        self.sequence.clear();
+        self.sequence_ends = InputState::default();
+        self.mapping_state.clear();
+        if let Some(profile) = active_profile(config, self.profile_index) {
+            for mapping in profile.mappings.iter() {
+                self.mapping_state
+                    .push(if self.inputs.is_all_pressed(&mapping.input) {
+                        MappingState::WaitingForRelease
+                    } else {
+                        MappingState::Released
+                    });
+            }
+        }
+    }
+
+    /// Adds `input` to the sequence of recent presses and fires any `Trigger::Sequence` mapping that it completes.
+    fn record_press(&mut self, config: &ArchivedConfig, input: DpedalInput) {
+        if self.now > self.last_pressed_at + self.sequence_timeout {
+            self.sequence.clear();
+        }
+        self.last_pressed_at = self.now;
+        if self.sequence.is_full() {
+            self.sequence.remove(0);
+        }
+        self.sequence.push(input);
+
+        let Some(profile) = active_profile(config, self.profile_index) else {
+            return;
+        };
+        self.sync_mapping_state(profile);
+        let mut completed = false;
+        for (i, (mapping, mapping_state)) in profile
+            .mappings
+            .iter()
+            .zip(self.mapping_state.iter_mut())
+            .enumerate()
+        {
+            if matches!(mapping.trigger, ArchivedTrigger::Sequence)
+                && matches!(mapping_state, MappingState::Released)
+                && !mapping.input.is_empty()
+                && sequence_ends_with(&self.sequence, &mapping.input)
+            {
+                if let Some(profile_index) = requested_profile(config, self.profile_index, mapping)
+                {
+                    self.pending_profile = Some(profile_index);
+                }
+                *mapping_state = MappingState::Pressing {
+                    next: 0,
+                    since: self.now,
+                };
+                let _ = self.triggered.try_push((self.profile_index, i));
+                completed = true;
+            }
+        }
+
+        if completed {
+            // Start over so that e.g. a third press does not complete an `a, a` sequence again.
+            self.sequence.clear();
+            self.sequence_ends.set_pressed(input);
+            // The outputs are released again by the next evaluation.
+            self.tap_pending = true;
+        }
+    }
+
+    /// Synchronizes the length of `mapping_state` with the profile in case it was set up before the config was first loaded.
+    fn sync_mapping_state(&mut self, profile: &ArchivedProfile) {
+        self.mapping_state.truncate(profile.mappings.len());
+        while profile.mappings.len() > self.mapping_state.len() {
+            self.mapping_state.push(MappingState::Released);
+        }
+    }
+
+    fn effective_inputs(&self) -> InputState {
+        self.inputs.without(self.held_back)
+    }
+
+    /// Holds back newly pressed inputs that are part of a chord, so that pressing the whole chord does not first fire
+    /// the mappings of whichever input happened to be pressed first.
+    /// Returns the held back inputs that were released before their chord was completed.
+    fn hold_back_chords(&mut self, config: &ArchivedConfig, previous: InputState) -> InputState {
+        let taps = self.held_back.without(self.inputs);
+        self.held_back = self.held_back.intersection(self.inputs);
+
+        let Some(profile) = active_profile(config, self.profile_index) else {
+            return taps;
+        };
+        let chords = || {
+            profile
+                .mappings
+                .iter()
+                .filter(|mapping| {
+                    matches!(mapping.trigger, ArchivedTrigger::Chord) && mapping.input.len() > 1
+                })
+                .map(|mapping| InputState::from_archived(&mapping.input))
+        };
+
+        let newly_pressed = self.inputs.without(previous);
+        let chord_inputs = chords().fold(InputState::default(), InputState::union);
+        let hold_back = newly_pressed.intersection(chord_inputs);
+        if !hold_back.is_empty() {
+            if self.held_back.is_empty() {
+                self.held_back_since = self.now;
+            }
+            self.held_back = self.held_back.union(hold_back);
+        }
+
+        // A completed chord no longer needs to wait.
+        for chord in chords() {
+            if self.inputs.intersection(chord) == chord {
+                self.held_back = self.held_back.without(chord);
+            }
+        }
+        taps
+    }
+
+    fn evaluate(&mut self, config: &ArchivedConfig, inputs: InputState) {
+        let Some(profile) = active_profile(config, self.profile_index) else {
+            return;
+        };
+        self.sync_mapping_state(profile);
+
+        // The most specific mappings win, so a chord suppresses the mappings of its individual inputs.
+        // Mappings with the same number of inputs do not suppress each other.
+        // The end of a just completed sequence has already been taken by the sequence.
+        let mut wins = [false; MAX_MAPPINGS];
+        let mut consumed = self.sequence_ends.intersection(inputs);
+        self.sequence_ends = self.sequence_ends.intersection(self.inputs);
+        for len in (1..=MAX_MAPPING_INPUTS).rev() {
+            let mut consumed_by_len = InputState::default();
+            for (mapping, wins) in profile.mappings.iter().zip(wins.iter_mut()) {
+                let mapping_inputs = InputState::from_archived(&mapping.input);
+                if mapping.input.len() == len
+                    && is_chord_pressed(mapping, inputs)
+                    && consumed.intersection(mapping_inputs).is_empty()
+                {
+                    *wins = true;
+                    consumed_by_len = consumed_by_len.union(mapping_inputs);
+                }
+            }
+            consumed = consumed.union(consumed_by_len);
+        }
+
+        for (i, ((mapping, mapping_state), wins)) in profile
+            .mappings
+            .iter()
+            .zip(self.mapping_state.iter_mut())
+            .zip(wins)
+            .enumerate()
+        {
+            // Sequences are only pressed by `record_press`, so are always released here.
+            let is_pressed = is_chord_pressed(mapping, inputs);
+            match mapping_state {
+                MappingState::Released if is_pressed && !wins => {
+                    *mapping_state = MappingState::WaitingForRelease
+                }
+                MappingState::Released if is_pressed => {
+                    if let Some(profile_index) =
+                        requested_profile(config, self.profile_index, mapping)
+                    {
+                        self.pending_profile = Some(profile_index);
+                    }
+                    *mapping_state = MappingState::Pressing {
+                        next: 0,
+                        since: self.now,
+                    };
+                    let _ = self.triggered.try_push((self.profile_index, i));
+                }
+                MappingState::Pressed { .. } if !wins => {
+                    *mapping_state = MappingState::Releasing {
+                        next: 0,
+                        reason: if is_pressed {
+                            ReleaseReason::Interrupted
+                        } else {
+                            ReleaseReason::InputsReleased
+                        },
+                    }
+                }
+                MappingState::WaitingForRelease if !is_pressed => {
+                    *mapping_state = MappingState::Released
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Falls back to the first profile if the active profile no longer exists in the config.
+fn active_profile(config: &ArchivedConfig, profile_index: usize) -> Option<&ArchivedProfile> {
+    config
+        .profiles
+        .get(profile_index)
+        .or(config.profiles.first())
+}
+
+/// Returns the profile to switch to if the mapping outputs `DPedalControl::NextProfile`.
+fn requested_profile(
+    config: &ArchivedConfig,
+    profile_index: usize,
+    mapping: &ArchivedMapping,
+) -> Option<usize> {
+    let next_profile = mapping
+        .output
+        .iter()
+        .any(|output| {
+            matches!(
+                output,
+                ArchivedComputerInput::Control(ArchivedDPedalControl::NextProfile)
+            )
+        })
+        .then(|| (profile_index + 1) % config.profiles.len())?;
+    (next_profile != profile_index).then_some(next_profile)
+}
+
+fn sequence_ends_with(sequence: &[DpedalInput], inputs: &[ArchivedDpedalInput]) -> bool {
+    inputs.len() <= sequence.len()
+        && sequence[sequence.len() - inputs.len()..]
+            .iter()
+            .zip(inputs)
+            .all(|(pressed, input)| *pressed == from_archived(input))
+}
+
+fn is_chord_pressed(mapping: &ArchivedMapping, inputs: InputState) -> bool {
+    matches!(mapping.trigger, ArchivedTrigger::Chord) && inputs.is_all_pressed(&mapping.input)
+}
+
+/// Returns the output at `next` that is sent to the computer, skipping any outputs handled by the engine itself.
+fn next_device_output(
+    outputs: &[ArchivedComputerInput],
+    next: &mut usize,
+) -> Option<ComputerInput> {
+    while let Some(output) = outputs.get(*next) {
+        *next += 1;
+        if let Some(output) = device_output(output) {
+            return Some(output);
+        }
+    }
+    None
+}
+
+/// Returns the output if it is sent to the computer, rather than handled by the engine itself.
+fn device_output(output: &ArchivedComputerInput) -> Option<ComputerInput> {
+    match from_archived(output) {
+        ComputerInput::None | ComputerInput::Control(_) => None,
+        output => Some(output),
+    }
+}
+
+enum MappingState {
+    Released,
+    /// The outputs before `next` have been pressed.
+    Pressing {
+        next: usize,
+        since: Millis,
+    },
+    Pressed {
+        since: Millis,
+    },
+    /// The outputs before `next` have been released.
+    Releasing {
+        next: usize,
+        reason: ReleaseReason,
+    },
+    /// The release outputs before `next` have been pressed.
+    PressingReleaseOutput {
+        next: usize,
+    },
+    /// The release outputs before `next` have been released.
+    ReleasingReleaseOutput {
+        next: usize,
+    },
+    /// The mapping's inputs were already held when its profile became active, were held past `Config::max_hold_ms`,
+    /// or were taken by a chord. The mapping does not fire until they are released.
+    WaitingForRelease,
+}
+
+impl MappingState {
+    /// Moves through the pressing and releasing states, returning the next action of this mapping if there is one.
+    fn advance(&mut self, mapping: &ArchivedMapping) -> Option<Action> {
+        loop {
+            *self = match self {
+                MappingState::Pressing { next, since } => {
+                    if let Some(output) = next_device_output(&mapping.output, next) {
+                        return Some(Action::Pressed(output));
+                    }
+                    MappingState::Pressed { since: *since }
+                }
+                MappingState::Releasing { next, reason } => {
+                    if let Some(output) = next_device_output(&mapping.output, next) {
+                        return Some(Action::Released(output));
+                    }
+                    match reason {
+                        ReleaseReason::InputsReleased => {
+                            MappingState::PressingReleaseOutput { next: 0 }
+                        }
+                        ReleaseReason::Interrupted => MappingState::WaitingForRelease,
+                        ReleaseReason::ProfileChanged => MappingState::Released,
+                    }
+                }
+                MappingState::PressingReleaseOutput { next } => {
+                    if let Some(output) = next_device_output(&mapping.release_output, next) {
+                        return Some(Action::Pressed(output));
+                    }
+                    MappingState::ReleasingReleaseOutput { next: 0 }
+                }
+                MappingState::ReleasingReleaseOutput { next } => {
+                    if let Some(output) = next_device_output(&mapping.release_output, next) {
+                        return Some(Action::Released(output));
+                    }
+                    MappingState::Released
+                }
+                MappingState::Pressed { .. }
+                | MappingState::Released
+                | MappingState::WaitingForRelease => return None,
+            };
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ReleaseReason {
+    /// The mapping's inputs were released, so its release outputs are tapped afterwards.
+    InputsReleased,
+    /// Released by the `Config::max_hold_ms` limit or a chord while the inputs are still held,
+    /// so the mapping waits for them to be released.
+    Interrupted,
+    /// Released so that the active profile can be switched.
+    ProfileChanged,
+}
+
+/// Bitset of the currently pressed inputs, indexed by `DpedalInput` discriminant.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct InputState(u32);
+
+impl InputState {
+    pub fn set_pressed(&mut self, input: DpedalInput) {
+        self.0 |= 1 << input as u32;
+    }
+
+    pub fn set_released(&mut self, input: DpedalInput) {
+        self.0 &= !(1 << input as u32);
+    }
+
+    pub fn is_pressed(&self, input: DpedalInput) -> bool {
+        self.0 & (1 << input as u32) != 0
+    }
+
+    fn from_archived(inputs: &[ArchivedDpedalInput]) -> Self {
+        let mut state = InputState::default();
+        for input in inputs {
+            state.set_pressed(from_archived(input));
+        }
+        state
+    }
+
+    fn union(self, other: InputState) -> Self {
+        InputState(self.0 | other.0)
+    }
+
+    fn intersection(self, other: InputState) -> Self {
+        InputState(self.0 & other.0)
+    }
+
+    fn without(self, other: InputState) -> Self {
+        InputState(self.0 & !other.0)
+    }
+
+    fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    fn is_all_pressed(&self, check: &[ArchivedDpedalInput]) -> bool {
+        // Disable the mapping when the inputs are entirely empty
+        // It is an obvious configuration mistake and having it constantly trigger the input would be very annoying
+        if check.is_empty() {
+            return false;
+        }
+
+        check
+            .iter()
+            .all(|input| self.is_pressed(from_archived(input)))
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,295 @@
+//! Builds the HID reports that are sent to the computer every USB poll.
+
+use arrayvec::ArrayVec;
+use dpedal_config::{ANALOG_FULL_TRAVEL, KeyboardInput, MAX_ANALOG_INPUTS, MouseInput};
+
+/// The fields of a boot protocol keyboard report.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct KeyboardReport {
+    pub modifier: u8,
+    pub keycodes: [u8; 6],
+}
+
+pub struct KeyboardReportBuilder {
+    report: KeyboardReport,
+    /// The report most recently returned by `next_report`.
+    last_report: KeyboardReport,
+    /// How many mappings are holding each keycode, a key is only released once none of them are.
+    hold_counts: [u8; 256],
+}
+
+impl Default for KeyboardReportBuilder {
+    fn default() -> Self {
+        KeyboardReportBuilder {
+            report: KeyboardReport::default(),
+            last_report: KeyboardReport::default(),
+            hold_counts: [0; 256],
+        }
+    }
+}
+
+impl KeyboardReportBuilder {
+    /// Returns true if `key` was pressed or released since the last report.
+    /// The report must then be sent before `key` changes again, otherwise a quick tap could be pressed and released between polls without the host ever seeing it.
+    pub fn is_unreported(&self, key: KeyboardInput) -> bool {
+        let keycode = key as u8;
+        self.report.keycodes.contains(&keycode) != self.last_report.keycodes.contains(&keycode)
+    }
+
+    /// Returns true if any key was pressed or released since the last report.
+    /// The report must then be sent before `release_all`, which would otherwise undo the change before the host sees it.
+    pub fn any_unreported(&self) -> bool {
+        self.report != self.last_report
+    }
+
+    pub fn pressed(&mut self, key: KeyboardInput) {
+        let keycode = key as u8;
+        let count = &mut self.hold_counts[keycode as usize];
+        *count = count.saturating_add(1);
+
+        // if keycode already set, do nothing
+        for check_keycode in &mut self.report.keycodes {
+            if *check_keycode == keycode {
+                return;
+            }
+        }
+
+        // Set an empty slot to the keycode
+        for check_keycode in &mut self.report.keycodes {
+            if *check_keycode == 0 {
+                *check_keycode = keycode;
+                return;
+            }
+        }
+    }
+
+    pub fn released(&mut self, key: KeyboardInput) {
+        let keycode = key as u8;
+        let count = &mut self.hold_counts[keycode as usize];
+        *count = count.saturating_sub(1);
+        if *count > 0 {
+            return;
+        }
+
+        for check_keycode in &mut self.report.keycodes {
+            if *check_keycode == keycode {
+                *check_keycode = 0;
+            }
+        }
+    }
+
+    /// Releases every key, no matter how many mappings are holding it.
+    pub fn release_all(&mut self) {
+        self.report.keycodes = [0; 6];
+        self.report.modifier = 0;
+        self.hold_counts = [0; 256];
+    }
+
+    /// Returns the report to send for the next poll.
+    pub fn next_report(&mut self) -> KeyboardReport {
+        self.last_report = self.report;
+        self.report
+    }
+}
+
+/// The fields of a boot protocol mouse report.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+#[derive(Default)]
+pub struct MouseReportBuilder {
+    report: MouseReport,
+    /// The buttons of the report most recently returned by `next_report`.
+    last_buttons: u8,
+    /// How many mappings are holding each button, indexed by bit position in `MouseReport::buttons`.
+    button_hold_counts: [u8; 3],
+    ticks: u32,
+    analog: [AnalogState; MAX_ANALOG_INPUTS],
+    /// Scroll and move inputs keep moving for as long as they are held, along with how many mappings are holding them.
+    held_motion: ArrayVec<(MouseInput, u8), MAX_HELD_MOTION>,
+}
+
+impl MouseReportBuilder {
+    /// Returns true if the click `input` was pressed or released since the last report.
+    /// The report must then be sent before `input` changes again, otherwise a quick click could be pressed and released between polls without the host ever seeing it.
+    /// Scroll and move inputs always move when pressed, so never need to be sent early.
+    pub fn is_unreported(&self, input: MouseInput) -> bool {
+        let Some(bit) = button_bit(input) else {
+            return false;
+        };
+        (self.report.buttons ^ self.last_buttons) & bit != 0
+    }
+
+    /// Returns true if any click was pressed or released since the last report.
+    /// The report must then be sent before `release_all`, which would otherwise undo the change before the host sees it.
+    pub fn any_unreported(&self) -> bool {
+        self.report.buttons != self.last_buttons
+    }
+
+    pub fn pressed(&mut self, input: MouseInput) {
+        match button_bit(input) {
+            Some(bit) => {
+                let count = &mut self.button_hold_counts[bit.trailing_zeros() as usize];
+                *count = count.saturating_add(1);
+                self.report.buttons |= bit;
+            }
+            None => match self.held_motion.iter_mut().find(|(held, _)| *held == input) {
+                Some((_, count)) => *count = count.saturating_add(1),
+                None => {
+                    // Move straight away so that quick taps, such as a rotary encoder detent, move at least once.
+                    digital_motion(&mut self.report, 0, input);
+                    // This can only fail if the same direction is held with several different values, the extra inputs are then only moved once.
+                    let _ = self.held_motion.try_push((input, 1));
+                }
+            },
+        }
+    }
+
+    pub fn released(&mut self, input: MouseInput) {
+        match button_bit(input) {
+            Some(bit) => {
+                let count = &mut self.button_hold_counts[bit.trailing_zeros() as usize];
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    self.report.buttons &= !bit;
+                }
+            }
+            None => {
+                if let Some(i) = self.held_motion.iter().position(|(held, _)| *held == input) {
+                    self.held_motion[i].1 -= 1;
+                    if self.held_motion[i].1 == 0 {
+                        self.held_motion.remove(i);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Releases every button and stops every held scroll and move input, no matter how many mappings are holding them.
+    /// Analog inputs are left alone since they are continuously driven by their pedal.
+    pub fn release_all(&mut self) {
+        self.report.buttons = 0;
+        self.button_hold_counts = [0; 3];
+        self.held_motion.clear();
+    }
+
+    /// Continuously drive a scroll or move input, amount is in thousandths of full travel.
+    pub fn analog(&mut self, index: u8, input: MouseInput, amount: u16) {
+        if let Some(state) = self.analog.get_mut(index as usize) {
+            state.input = input;
+            state.amount = amount;
+        }
+    }
+
+    /// Returns the report to send for the next poll, which occurs every millisecond.
+    pub fn next_report(&mut self) -> MouseReport {
+        self.ticks = self.ticks.wrapping_add(1);
+        for (input, _) in &self.held_motion {
+            digital_motion(&mut self.report, self.ticks, *input);
+        }
+        for state in &mut self.analog {
+            analog_motion(&mut self.report, state);
+        }
+
+        let report = self.report;
+        self.last_buttons = report.buttons;
+
+        // reset non-button inputs
+        self.report.wheel = 0;
+        self.report.x = 0;
+        self.report.y = 0;
+        self.report.pan = 0;
+
+        report
+    }
+}
+
+fn button_bit(input: MouseInput) -> Option<u8> {
+    match input {
+        MouseInput::ClickLeft => Some(0b0000_0001),
+        MouseInput::ClickRight => Some(0b0000_0010),
+        MouseInput::ClickMiddle => Some(0b0000_0100),
+        _ => None,
+    }
+}
+
+/// Enough for every scroll and move direction to be held at once.
+const MAX_HELD_MOTION: usize = 8;
+
+fn digital_motion(report: &mut MouseReport, ticks: u32, input: MouseInput) {
+    match input {
+        MouseInput::ScrollUp(value) => scroll(report, ticks, 0, (value / 10) as i8),
+        MouseInput::ScrollDown(value) => scroll(report, ticks, 0, (value / -10) as i8),
+        MouseInput::ScrollLeft(value) => scroll(report, ticks, (value / -10) as i8, 0),
+        MouseInput::ScrollRight(value) => scroll(report, ticks, (value / 10) as i8, 0),
+        MouseInput::MoveUp(value) => move_cursor(report, ticks, 0, (value / -10) as i8),
+        MouseInput::MoveDown(value) => move_cursor(report, ticks, 0, (value / 10) as i8),
+        MouseInput::MoveLeft(value) => move_cursor(report, ticks, (value / -10) as i8, 0),
+        MouseInput::MoveRight(value) => move_cursor(report, ticks, (value / 10) as i8, 0),
+        MouseInput::ClickLeft | MouseInput::ClickMiddle | MouseInput::ClickRight => {}
+    }
+}
+
+fn scroll(report: &mut MouseReport, ticks: u32, x: i8, y: i8) {
+    if ticks.is_multiple_of(80) {
+        report.pan += x;
+        report.wheel += y;
+    }
+}
+
+fn move_cursor(report: &mut MouseReport, ticks: u32, x: i8, y: i8) {
+    if ticks.is_multiple_of(80) {
+        report.x += x;
+        report.y += y;
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct AnalogState {
+    input: MouseInput,
+    amount: u16,
+    /// Motion that has built up but is not yet large enough to be sent in a report.
+    accumulator: i32,
+}
+
+/// An analog input at full travel moves at the same rate as a held digital input with the same value.
+/// Digital inputs move by value / 10 every 80 ticks and analog amounts are in thousandths of full travel.
+const ANALOG_MOTION_UNIT: i32 = 10 * 80 * ANALOG_FULL_TRAVEL as i32;
+
+fn analog_motion(report: &mut MouseReport, state: &mut AnalogState) {
+    let (value, x, y) = match state.input {
+        MouseInput::ScrollUp(value) | MouseInput::MoveDown(value) => (value, 0, 1),
+        MouseInput::ScrollDown(value) | MouseInput::MoveUp(value) => (value, 0, -1),
+        MouseInput::ScrollRight(value) | MouseInput::MoveRight(value) => (value, 1, 0),
+        MouseInput::ScrollLeft(value) | MouseInput::MoveLeft(value) => (value, -1, 0),
+        MouseInput::ClickLeft | MouseInput::ClickMiddle | MouseInput::ClickRight => return,
+    };
+    if state.amount == 0 {
+        state.accumulator = 0;
+        return;
+    }
+
+    state.accumulator += value as i32 * state.amount as i32;
+    let units = (state.accumulator / ANALOG_MOTION_UNIT) as i8;
+    state.accumulator %= ANALOG_MOTION_UNIT;
+
+    match state.input {
+        MouseInput::ScrollUp(_)
+        | MouseInput::ScrollDown(_)
+        | MouseInput::ScrollRight(_)
+        | MouseInput::ScrollLeft(_) => {
+            report.pan = report.pan.saturating_add(units.saturating_mul(x));
+            report.wheel = report.wheel.saturating_add(units.saturating_mul(y));
+        }
+        _ => {
+            report.x = report.x.saturating_add(units.saturating_mul(x));
+            report.y = report.y.saturating_add(units.saturating_mul(y));
+        }
+    }
+}
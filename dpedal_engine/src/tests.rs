@@ -0,0 +1,718 @@
+use super::*;
+use arrayvec::ArrayVec;
+use dpedal_config::storage::{access_config, encode_config};
+use dpedal_config::{
+    CONFIG_SIZE, Config, DPedalControl, KeyboardInput, Mapping, MouseInput, Profile, Trigger,
+};
+use rkyv::util::Align;
+
+fn mapping(input: &[DpedalInput], output: &[ComputerInput]) -> Mapping {
+    Mapping {
+        trigger: Trigger::Chord,
+        input: input.iter().copied().collect(),
+        output: output.iter().copied().collect(),
+        release_output: ArrayVec::new(),
+    }
+}
+
+fn sequence(input: &[DpedalInput], output: &[ComputerInput]) -> Mapping {
+    Mapping {
+        trigger: Trigger::Sequence,
+        ..mapping(input, output)
+    }
+}
+
+fn config(profiles: &[&[Mapping]]) -> Config {
+    Config {
+        profiles: profiles
+            .iter()
+            .map(|mappings| Profile {
+                mappings: mappings.iter().cloned().collect(),
+            })
+            .collect(),
+        ..Config::default()
+    }
+}
+
+fn encode(config: &Config) -> (Align<[u8; CONFIG_SIZE]>, usize) {
+    let mut buffer = Align([0u8; CONFIG_SIZE]);
+    let len = encode_config(config, &mut *buffer).unwrap();
+    (buffer, len)
+}
+
+fn inputs(pressed: &[DpedalInput]) -> InputState {
+    let mut state = InputState::default();
+    for input in pressed {
+        state.set_pressed(*input);
+    }
+    state
+}
+
+fn drain(engine: &mut Engine, config: &ArchivedConfig) -> ArrayVec<Action, 64> {
+    let mut actions = ArrayVec::new();
+    while let Some(action) = engine.next_action(config) {
+        actions.push(action);
+    }
+    actions
+}
+
+/// Creates an engine for `config`, loading the config also releases everything.
+fn new_engine(config: &ArchivedConfig) -> Engine {
+    let mut engine = Engine::new(0);
+    engine.config_changed(config);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::ReleaseAll]);
+    engine
+}
+
+const A: ComputerInput = ComputerInput::Keyboard(KeyboardInput::A);
+const B: ComputerInput = ComputerInput::Keyboard(KeyboardInput::B);
+const CLICK: ComputerInput = ComputerInput::Mouse(MouseInput::ClickLeft);
+const NEXT_PROFILE: ComputerInput = ComputerInput::Control(DPedalControl::NextProfile);
+
+#[test]
+fn test_debounce() {
+    let (buffer, len) = encode(&config(&[&[mapping(&[DpedalInput::DpadUp], &[A])]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    // A bounce that settles back to released within the debounce time is ignored.
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 0);
+    assert_eq!(engine.next_deadline(), Some(DEBOUNCE_MS));
+    engine.update(config, inputs(&[]), 2);
+    assert_eq!(engine.next_deadline(), None);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+
+    // Every change restarts the debounce time.
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 10);
+    engine.update(config, inputs(&[]), 12);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 13);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 16);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    assert_eq!(engine.next_deadline(), Some(13 + DEBOUNCE_MS));
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 18);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+    assert_eq!(engine.next_deadline(), None);
+
+    engine.update(config, inputs(&[]), 30);
+    engine.update(config, inputs(&[]), 35);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Released(A)]
+    );
+}
+
+#[test]
+fn test_chord() {
+    let (buffer, len) = encode(&config(&[&[mapping(
+        &[DpedalInput::ButtonLeft, DpedalInput::ButtonRight],
+        &[A, CLICK],
+    )]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 0);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+
+    let both = inputs(&[DpedalInput::ButtonLeft, DpedalInput::ButtonRight]);
+    engine.update(config, both, 10);
+    engine.update(config, both, 15);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Pressed(A), Action::Pressed(CLICK)]
+    );
+
+    engine.update(config, inputs(&[DpedalInput::ButtonRight]), 20);
+    engine.update(config, inputs(&[DpedalInput::ButtonRight]), 25);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Released(A), Action::Released(CLICK)]
+    );
+}
+
+fn chord_config() -> Config {
+    config(&[&[
+        mapping(&[DpedalInput::ButtonLeft], &[A]),
+        mapping(&[DpedalInput::ButtonRight], &[B]),
+        mapping(
+            &[DpedalInput::ButtonLeft, DpedalInput::ButtonRight],
+            &[CLICK],
+        ),
+    ]])
+}
+
+#[test]
+fn test_chord_suppresses_single_inputs() {
+    let (buffer, len) = encode(&chord_config());
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 0);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    assert_eq!(
+        engine.next_deadline(),
+        Some(5 + DEFAULT_COMBO_WINDOW_MS as Millis)
+    );
+
+    let both = inputs(&[DpedalInput::ButtonLeft, DpedalInput::ButtonRight]);
+    engine.update(config, both, 20);
+    engine.update(config, both, 25);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Pressed(CLICK)]
+    );
+    assert_eq!(engine.next_deadline(), None);
+
+    // Releasing part of the chord does not fire the inputs still held.
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 30);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 35);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Released(CLICK)]
+    );
+    engine.update(config, inputs(&[]), 40);
+    engine.update(config, inputs(&[]), 45);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+}
+
+#[test]
+fn test_triggered_mappings() {
+    let (buffer, len) = encode(&chord_config());
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    let both = inputs(&[DpedalInput::ButtonLeft, DpedalInput::ButtonRight]);
+    engine.update(config, both, 0);
+    engine.update(config, both, 5);
+    drain(&mut engine, config);
+    assert_eq!(engine.take_triggered_mappings().as_slice(), &[(0, 2)]);
+    assert_eq!(engine.take_triggered_mappings().as_slice(), &[]);
+
+    // Releasing the chord does not fire anything.
+    engine.update(config, inputs(&[]), 10);
+    engine.update(config, inputs(&[]), 15);
+    drain(&mut engine, config);
+    assert_eq!(engine.take_triggered_mappings().as_slice(), &[]);
+
+    press_and_release(&mut engine, config, DpedalInput::ButtonLeft, 20);
+    assert_eq!(engine.take_triggered_mappings().as_slice(), &[(0, 0)]);
+}
+
+#[test]
+fn test_chord_after_combo_window() {
+    let (buffer, len) = encode(&chord_config());
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 0);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 5);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 54);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 55);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+
+    // Completing the chord late still takes over from the single input.
+    let both = inputs(&[DpedalInput::ButtonLeft, DpedalInput::ButtonRight]);
+    engine.update(config, both, 100);
+    engine.update(config, both, 105);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Released(A), Action::Pressed(CLICK)]
+    );
+}
+
+#[test]
+fn test_chord_input_released_early() {
+    let (buffer, len) = encode(&chord_config());
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 0);
+    engine.update(config, inputs(&[DpedalInput::ButtonLeft]), 5);
+    engine.update(config, inputs(&[]), 20);
+    engine.update(config, inputs(&[]), 25);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Pressed(A), Action::Released(A)]
+    );
+    assert_eq!(engine.next_deadline(), None);
+}
+
+/// Presses and releases `input`, each held long enough to pass debouncing, returning the resulting actions.
+fn press_and_release(
+    engine: &mut Engine,
+    config: &ArchivedConfig,
+    input: DpedalInput,
+    at: Millis,
+) -> ArrayVec<Action, 64> {
+    engine.update(config, inputs(&[input]), at);
+    engine.update(config, inputs(&[input]), at + DEBOUNCE_MS);
+    let mut actions = drain(engine, config);
+    engine.update(config, inputs(&[]), at + 10);
+    engine.update(config, inputs(&[]), at + 10 + DEBOUNCE_MS);
+    actions.extend(drain(engine, config));
+    actions
+}
+
+#[test]
+fn test_sequence() {
+    let (buffer, len) = encode(&config(&[&[
+        mapping(&[DpedalInput::DpadDown], &[B]),
+        sequence(
+            &[
+                DpedalInput::DpadUp,
+                DpedalInput::DpadUp,
+                DpedalInput::DpadDown,
+            ],
+            &[A],
+        ),
+    ]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    assert_eq!(
+        press_and_release(&mut engine, config, DpedalInput::DpadUp, 0).as_slice(),
+        &[]
+    );
+    assert_eq!(
+        press_and_release(&mut engine, config, DpedalInput::DpadUp, 100).as_slice(),
+        &[]
+    );
+
+    // The last press of the sequence does not also fire its own mapping.
+    engine.update(config, inputs(&[DpedalInput::DpadDown]), 200);
+    engine.update(config, inputs(&[DpedalInput::DpadDown]), 205);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Pressed(A), Action::Released(A)]
+    );
+    engine.update(config, inputs(&[]), 210);
+    engine.update(config, inputs(&[]), 215);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+
+    // The sequence starts over once it has fired.
+    assert_eq!(
+        press_and_release(&mut engine, config, DpedalInput::DpadDown, 300).as_slice(),
+        &[Action::Pressed(B), Action::Released(B)]
+    );
+}
+
+#[test]
+fn test_sequence_timeout() {
+    let (buffer, len) = encode(&config(&[&[sequence(
+        &[DpedalInput::DpadUp, DpedalInput::Encoder1Clockwise],
+        &[A],
+    )]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    assert_eq!(
+        press_and_release(&mut engine, config, DpedalInput::DpadUp, 0).as_slice(),
+        &[]
+    );
+    engine.tap(config, DpedalInput::Encoder1Clockwise, 20);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Pressed(A), Action::Released(A)]
+    );
+
+    assert_eq!(
+        press_and_release(&mut engine, config, DpedalInput::DpadUp, 100).as_slice(),
+        &[]
+    );
+    engine.update(
+        config,
+        inputs(&[]),
+        100 + DEFAULT_SEQUENCE_TIMEOUT_MS as Millis + 10,
+    );
+    engine.tap(
+        config,
+        DpedalInput::Encoder1Clockwise,
+        100 + DEFAULT_SEQUENCE_TIMEOUT_MS as Millis + 10,
+    );
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+}
+
+#[test]
+fn test_sequence_timeout_between_taps() {
+    let (buffer, len) = encode(&config(&[&[sequence(
+        &[
+            DpedalInput::Encoder1Clockwise,
+            DpedalInput::Encoder1CounterClockwise,
+        ],
+        &[A],
+    )]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.tap(config, DpedalInput::Encoder1Clockwise, 0);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    engine.tap(
+        config,
+        DpedalInput::Encoder1CounterClockwise,
+        DEFAULT_SEQUENCE_TIMEOUT_MS as Millis + 10,
+    );
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+
+    engine.tap(config, DpedalInput::Encoder1Clockwise, 1000);
+    engine.tap(config, DpedalInput::Encoder1CounterClockwise, 1010);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Pressed(A), Action::Released(A)]
+    );
+}
+
+#[test]
+fn test_release_output() {
+    let (buffer, len) = encode(&config(&[&[Mapping {
+        release_output: [B, CLICK].into_iter().collect(),
+        ..mapping(&[DpedalInput::ButtonLeft], &[A])
+    }]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    assert_eq!(
+        press_and_release(&mut engine, config, DpedalInput::ButtonLeft, 0).as_slice(),
+        &[
+            Action::Pressed(A),
+            Action::Released(A),
+            Action::Pressed(B),
+            Action::Pressed(CLICK),
+            Action::Released(B),
+            Action::Released(CLICK),
+        ]
+    );
+}
+
+#[test]
+fn test_tap() {
+    let (buffer, len) = encode(&config(&[&[mapping(
+        &[DpedalInput::Encoder1Clockwise],
+        &[A],
+    )]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    for i in 0..2 {
+        engine.tap(config, DpedalInput::Encoder1Clockwise, i * 10);
+        assert_eq!(
+            drain(&mut engine, config).as_slice(),
+            &[Action::Pressed(A), Action::Released(A)]
+        );
+    }
+}
+
+#[test]
+fn test_next_profile() {
+    let (buffer, len) = encode(&config(&[
+        &[
+            mapping(&[DpedalInput::DpadUp], &[A]),
+            mapping(&[DpedalInput::DpadDown], &[NEXT_PROFILE]),
+        ],
+        &[
+            mapping(&[DpedalInput::DpadDown], &[B]),
+            mapping(&[DpedalInput::DpadLeft], &[NEXT_PROFILE]),
+        ],
+    ]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 0);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+
+    // Outputs held in the previous profile are released before switching.
+    let held = inputs(&[DpedalInput::DpadUp, DpedalInput::DpadDown]);
+    engine.update(config, held, 10);
+    engine.update(config, held, 15);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[
+            Action::Released(A),
+            Action::ProfileChanged(1),
+            Action::ReleaseAll
+        ]
+    );
+    assert_eq!(engine.active_profile(), 1);
+
+    // The input that switched profile does not fire the new profile's mapping until it is released and pressed again.
+    engine.update(config, inputs(&[]), 20);
+    engine.update(config, inputs(&[]), 25);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    engine.update(config, inputs(&[DpedalInput::DpadDown]), 30);
+    engine.update(config, inputs(&[DpedalInput::DpadDown]), 35);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(B)]);
+
+    // Profiles wrap around.
+    let held = inputs(&[DpedalInput::DpadDown, DpedalInput::DpadLeft]);
+    engine.update(config, held, 40);
+    engine.update(config, held, 45);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[
+            Action::Released(B),
+            Action::ProfileChanged(0),
+            Action::ReleaseAll
+        ]
+    );
+}
+
+#[test]
+fn test_set_active_profile() {
+    let (buffer, len) = encode(&config(&[
+        &[mapping(&[DpedalInput::DpadUp], &[A])],
+        &[mapping(&[DpedalInput::DpadUp], &[B])],
+    ]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.set_active_profile(0);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+
+    engine.set_active_profile(1);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::ProfileChanged(1), Action::ReleaseAll]
+    );
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 0);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(B)]);
+}
+
+#[test]
+fn test_config_changed_removes_active_profile() {
+    let (buffer, len) = encode(&config(&[
+        &[mapping(&[DpedalInput::DpadUp], &[A])],
+        &[mapping(&[DpedalInput::DpadUp], &[B])],
+    ]));
+    let (one_profile_buffer, one_profile_len) =
+        encode(&config(&[&[mapping(&[DpedalInput::DpadUp], &[A])]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+    engine.set_active_profile(1);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::ProfileChanged(1), Action::ReleaseAll]
+    );
+
+    let config = access_config(&one_profile_buffer[..one_profile_len]).unwrap();
+    engine.config_changed(config);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::ReleaseAll, Action::ProfileChanged(0)]
+    );
+    assert_eq!(engine.active_profile(), 0);
+
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 0);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+}
+
+#[test]
+fn test_config_changed_while_held() {
+    let (buffer, len) = encode(&config(&[&[mapping(&[DpedalInput::DpadUp], &[A])]]));
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 0);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+
+    let (buffer, len) = encode(&config_with_b());
+    let config = access_config(&buffer[..len]).unwrap();
+    engine.config_changed(config);
+    // The previous config's mappings are gone, so A can only be released by releasing everything.
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::ReleaseAll]);
+
+    engine.update(config, inputs(&[]), 10);
+    engine.update(config, inputs(&[]), 15);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 20);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 25);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(B)]);
+}
+
+#[test]
+fn test_max_hold() {
+    let (buffer, len) = encode(&Config {
+        max_hold_ms: 100,
+        ..config(&[&[mapping(&[DpedalInput::DpadUp], &[A])]])
+    });
+    let config = access_config(&buffer[..len]).unwrap();
+    let mut engine = new_engine(config);
+
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 0);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 5);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+    assert_eq!(engine.next_deadline(), Some(105));
+
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 104);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 105);
+    assert_eq!(
+        drain(&mut engine, config).as_slice(),
+        &[Action::Released(A)]
+    );
+    assert_eq!(engine.next_deadline(), None);
+
+    // The mapping does not fire again until its input is released.
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 500);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[]);
+    engine.update(config, inputs(&[]), 510);
+    engine.update(config, inputs(&[]), 515);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 520);
+    engine.update(config, inputs(&[DpedalInput::DpadUp]), 525);
+    assert_eq!(drain(&mut engine, config).as_slice(), &[Action::Pressed(A)]);
+}
+
+fn config_with_b() -> Config {
+    config(&[&[mapping(&[DpedalInput::DpadUp], &[B])]])
+}
+
+#[test]
+fn test_keyboard_report() {
+    use report::KeyboardReportBuilder;
+
+    let mut builder = KeyboardReportBuilder::default();
+    builder.pressed(KeyboardInput::A);
+    builder.pressed(KeyboardInput::B);
+    assert_eq!(
+        builder.next_report().keycodes,
+        [KeyboardInput::A as u8, KeyboardInput::B as u8, 0, 0, 0, 0]
+    );
+    builder.released(KeyboardInput::A);
+    assert_eq!(
+        builder.next_report().keycodes,
+        [0, KeyboardInput::B as u8, 0, 0, 0, 0]
+    );
+}
+
+#[test]
+fn test_mouse_report() {
+    use report::{MouseReport, MouseReportBuilder};
+
+    let mut builder = MouseReportBuilder::default();
+    // A scroll that is released before the next report still scrolls once.
+    builder.pressed(MouseInput::ScrollUp(10));
+    builder.released(MouseInput::ScrollUp(10));
+    builder.pressed(MouseInput::ClickLeft);
+    assert_eq!(
+        builder.next_report(),
+        MouseReport {
+            buttons: 1,
+            wheel: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        builder.next_report(),
+        MouseReport {
+            buttons: 1,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn test_quick_tap_is_unreported() {
+    use report::{KeyboardReportBuilder, MouseReportBuilder};
+
+    let mut keyboard = KeyboardReportBuilder::default();
+    assert!(!keyboard.is_unreported(KeyboardInput::A));
+    keyboard.pressed(KeyboardInput::A);
+    // The release must wait until the press has been sent.
+    assert!(keyboard.is_unreported(KeyboardInput::A));
+    assert!(!keyboard.is_unreported(KeyboardInput::B));
+    keyboard.next_report();
+    assert!(!keyboard.is_unreported(KeyboardInput::A));
+    keyboard.released(KeyboardInput::A);
+    // As must a press that follows the release.
+    assert!(keyboard.is_unreported(KeyboardInput::A));
+
+    let mut mouse = MouseReportBuilder::default();
+    mouse.pressed(MouseInput::ClickRight);
+    assert!(mouse.is_unreported(MouseInput::ClickRight));
+    assert!(!mouse.is_unreported(MouseInput::ClickLeft));
+    mouse.pressed(MouseInput::ScrollUp(10));
+    assert!(!mouse.is_unreported(MouseInput::ScrollUp(10)));
+    mouse.next_report();
+    assert!(!mouse.is_unreported(MouseInput::ClickRight));
+}
+
+#[test]
+fn test_any_unreported() {
+    use report::{KeyboardReportBuilder, MouseReportBuilder};
+
+    let mut keyboard = KeyboardReportBuilder::default();
+    assert!(!keyboard.any_unreported());
+    keyboard.pressed(KeyboardInput::A);
+    // A tap queued before a release all must be sent first, otherwise the host never sees it.
+    assert!(keyboard.any_unreported());
+    keyboard.next_report();
+    assert!(!keyboard.any_unreported());
+    keyboard.released(KeyboardInput::A);
+    assert!(keyboard.any_unreported());
+    keyboard.next_report();
+    keyboard.release_all();
+    assert!(!keyboard.any_unreported());
+
+    let mut mouse = MouseReportBuilder::default();
+    mouse.pressed(MouseInput::ScrollUp(10));
+    assert!(!mouse.any_unreported());
+    mouse.pressed(MouseInput::ClickLeft);
+    assert!(mouse.any_unreported());
+    mouse.next_report();
+    assert!(!mouse.any_unreported());
+    mouse.released(MouseInput::ClickLeft);
+    assert!(mouse.any_unreported());
+}
+
+#[test]
+fn test_overlapping_holds() {
+    use report::{KeyboardReportBuilder, MouseReport, MouseReportBuilder};
+
+    let mut keyboard = KeyboardReportBuilder::default();
+    keyboard.pressed(KeyboardInput::LeftShift);
+    keyboard.pressed(KeyboardInput::LeftShift);
+    keyboard.released(KeyboardInput::LeftShift);
+    assert_eq!(
+        keyboard.next_report().keycodes,
+        [KeyboardInput::LeftShift as u8, 0, 0, 0, 0, 0]
+    );
+    keyboard.released(KeyboardInput::LeftShift);
+    assert_eq!(keyboard.next_report().keycodes, [0; 6]);
+    // Releasing a key that is not held does not affect later presses.
+    keyboard.released(KeyboardInput::LeftShift);
+    keyboard.pressed(KeyboardInput::LeftShift);
+    assert_eq!(
+        keyboard.next_report().keycodes,
+        [KeyboardInput::LeftShift as u8, 0, 0, 0, 0, 0]
+    );
+
+    let mut mouse = MouseReportBuilder::default();
+    mouse.pressed(MouseInput::ClickLeft);
+    mouse.pressed(MouseInput::ClickLeft);
+    mouse.pressed(MouseInput::ScrollUp(10));
+    mouse.pressed(MouseInput::ScrollUp(10));
+    mouse.released(MouseInput::ClickLeft);
+    mouse.released(MouseInput::ScrollUp(10));
+    for _ in 0..79 {
+        mouse.next_report();
+    }
+    // Both are still held by the other mapping, so the button stays down and scrolling continues.
+    assert_eq!(
+        mouse.next_report(),
+        MouseReport {
+            buttons: 1,
+            wheel: 1,
+            ..Default::default()
+        }
+    );
+    mouse.released(MouseInput::ClickLeft);
+    mouse.released(MouseInput::ScrollUp(10));
+    for _ in 0..79 {
+        mouse.next_report();
+    }
+    assert_eq!(mouse.next_report(), MouseReport::default());
+}
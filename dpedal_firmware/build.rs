@@ -1,6 +1,22 @@
 use std::env;
+use std::process::Command;
 
 fn main() {
     let profile = env::var("PROFILE").unwrap();
     println!("cargo:rustc-env=PROFILE={}", profile);
+
+    // Builds outside of a git checkout, such as from a source tarball, report an empty hash.
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    // Truncated to fit `DeviceInfo::git_hash`, a SHA-256 repository would otherwise produce a 64 character hash.
+    let git_hash: String = git_hash.trim().chars().take(40).collect();
+    println!("cargo:rustc-env=GIT_HASH={git_hash}");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
 }
@@ -0,0 +1,129 @@
+use crate::config::{CONFIG, CONFIG_CHANGED};
+use crate::mouse::{MOUSE_CHANNEL, MOUSE_RELEASED_ALL, MouseEvent};
+use arrayvec::ArrayVec;
+use defmt::*;
+use dpedal_config::storage::from_archived;
+use dpedal_config::{ANALOG_FULL_TRAVEL, AnalogInput, AnalogOutput, MAX_ANALOG_INPUTS, MouseInput};
+use embassy_rp::adc::{self, Adc, AdcPin, Async, Channel};
+use embassy_rp::gpio::{AnyPin, Pin, Pull};
+use embassy_rp::peripherals::ADC;
+use embassy_rp::{Peri, bind_interrupts};
+use embassy_time::Timer;
+
+bind_interrupts!(struct Irqs {
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+});
+
+/// The first ADC capable pin, pins 26-29 map to ADC channels 0-3.
+const FIRST_ADC_PIN: u32 = 26;
+
+pub struct Analog {
+    adc: Adc<'static, Async>,
+    /// Indexed by ADC channel
+    channels: [Option<Channel<'static>>; 4],
+    /// Indexed by ADC channel
+    claimed: [bool; 4],
+}
+
+impl Analog {
+    pub async fn new(p_adc: Peri<'static, ADC>) -> Self {
+        let mut claimed = [false; 4];
+        // analog_inputs cant be set by the web configurator, so the set of pins used never changes after boot.
+        for analog_input in CONFIG.lock().await.get().analog_inputs.iter() {
+            let pin = analog_input.pin.to_native();
+            match pin.checked_sub(FIRST_ADC_PIN) {
+                Some(channel @ 0..4) => claimed[channel as usize] = true,
+                _ => error!(
+                    "Analog input pin {} is not ADC capable, must be one of 26-29",
+                    pin
+                ),
+            }
+        }
+
+        Analog {
+            adc: Adc::new(p_adc, Irqs, adc::Config::default()),
+            channels: [None, None, None, None],
+            claimed,
+        }
+    }
+
+    /// If an analog input is configured to use this pin, it is claimed for the ADC.
+    /// Otherwise the pin is returned so it can be used as a digital input.
+    pub fn claim_pin<T: AdcPin + Pin>(
+        &mut self,
+        pin: Peri<'static, T>,
+    ) -> Option<Peri<'static, AnyPin>> {
+        let channel = (pin.pin() as u32 - FIRST_ADC_PIN) as usize;
+        if self.claimed[channel] {
+            self.channels[channel] = Some(Channel::new_pin(pin, Pull::None));
+            None
+        } else {
+            Some(pin.into())
+        }
+    }
+
+    pub async fn process(&mut self) {
+        let mut last_amounts = [0u16; MAX_ANALOG_INPUTS];
+        let mut analog_inputs = ArrayVec::<AnalogInput, MAX_ANALOG_INPUTS>::new();
+        let mut config_changed = CONFIG_CHANGED.receiver().unwrap();
+        loop {
+            if MOUSE_RELEASED_ALL.try_take().is_some() {
+                // Clicks held by an analog input were released too, treat every input as starting from rest
+                // so that any still past their threshold are pressed again.
+                last_amounts = [0; MAX_ANALOG_INPUTS];
+            }
+            if config_changed.try_changed().is_some() {
+                analog_inputs = CONFIG
+                    .lock()
+                    .await
+                    .get()
+                    .analog_inputs
+                    .iter()
+                    .map(from_archived)
+                    .collect();
+            }
+            for (index, analog_input) in analog_inputs.iter().enumerate() {
+                let channel = analog_input.pin.wrapping_sub(FIRST_ADC_PIN) as usize;
+                let Some(Some(channel)) = self.channels.get_mut(channel) else {
+                    continue;
+                };
+                let raw = match self.adc.read(channel).await {
+                    Ok(raw) => raw,
+                    Err(e) => {
+                        warn!("Failed to read ADC: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let amount = analog_input.normalize(raw);
+                let last_amount = core::mem::replace(&mut last_amounts[index], amount);
+                if amount != last_amount {
+                    match analog_input.output {
+                        AnalogOutput::Mouse(
+                            input @ (MouseInput::ClickLeft
+                            | MouseInput::ClickMiddle
+                            | MouseInput::ClickRight),
+                        ) => {
+                            let half_travel = ANALOG_FULL_TRAVEL / 2;
+                            if amount >= half_travel && last_amount < half_travel {
+                                MOUSE_CHANNEL.send(MouseEvent::Pressed(input)).await;
+                            } else if amount < half_travel && last_amount >= half_travel {
+                                MOUSE_CHANNEL.send(MouseEvent::Released(input)).await;
+                            }
+                        }
+                        AnalogOutput::Mouse(input) => {
+                            MOUSE_CHANNEL
+                                .send(MouseEvent::Analog {
+                                    index: index as u8,
+                                    input,
+                                    amount,
+                                })
+                                .await
+                        }
+                    }
+                }
+            }
+            Timer::after_millis(10).await;
+        }
+    }
+}
@@ -1,100 +1,302 @@
 use arrayvec::ArrayVec;
-use defmt::error;
-use dpedal_config::{ArchivedConfig, CONFIG_OFFSET, CONFIG_SIZE, Config, RP2040_FLASH_SIZE};
+use core::cell::RefCell;
+use defmt::{error, info, warn};
+use dpedal_config::storage::{
+    self, CONFIG_HEADER_SIZE, CONFIG_SLOT_COUNT, ConfigHeader, StoredConfigError,
+    config_slot_offset,
+};
+use dpedal_config::web_config_protocol::SetConfigError;
+use dpedal_config::{ArchivedConfig, CONFIG_SIZE, Config, RP2040_FLASH_SIZE};
 use embassy_rp::{
     Peri,
-    flash::{Blocking, Flash},
+    flash::{self, Blocking, Flash},
     peripherals::FLASH,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_sync::{
+    blocking_mutex, blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, watch::Watch,
+};
 use rkyv::{rancor::Failure, util::Align};
+use static_cell::StaticCell;
 
-pub static CONFIG: Mutex<CriticalSectionRawMutex, Option<Config>> = Mutex::new(None);
+/// The current config, stored in rkyv format so that tasks can access it in place instead of copying it.
+pub static CONFIG: Mutex<CriticalSectionRawMutex, ConfigBuffer> = Mutex::new(ConfigBuffer::new());
+/// Notified whenever `CONFIG` is replaced, each task that needs to react to config changes holds a receiver.
+pub static CONFIG_CHANGED: Watch<CriticalSectionRawMutex, (), 4> = Watch::new();
+
+pub struct ConfigBuffer {
+    bytes: Align<[u8; CONFIG_SIZE]>,
+    len: usize,
+}
+
+impl ConfigBuffer {
+    const fn new() -> Self {
+        ConfigBuffer {
+            bytes: Align([0; CONFIG_SIZE]),
+            len: 0,
+        }
+    }
+
+    /// Must only be called after `ConfigFlash::new`, which always stores a config.
+    pub fn get(&self) -> &ArchivedConfig {
+        debug_assert!(self.len != 0);
+        // SAFETY: The bytes are always validated by `storage::access_config` or produced by `storage::encode_config` before `len` is set.
+        unsafe { rkyv::access_unchecked::<ArchivedConfig>(&self.bytes[..self.len]) }
+    }
+
+    fn set(&mut self, config: &Config) -> Result<(), ()> {
+        self.len = storage::encode_config(config, &mut *self.bytes).map_err(|_| ())?;
+        Ok(())
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn set_validated(&mut self, bytes: &[u8]) {
+        self.bytes[..bytes.len()].copy_from_slice(bytes);
+        self.len = bytes.len();
+    }
+}
+
+/// Flash is shared between the config and the runtime state, flash operations are blocking so a blocking mutex is used.
+pub type SharedFlash = blocking_mutex::Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Flash<'static, FLASH, Blocking, RP2040_FLASH_SIZE>>,
+>;
+
+pub fn shared_flash(p_flash: Peri<'static, FLASH>) -> &'static SharedFlash {
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    FLASH.init(blocking_mutex::Mutex::new(RefCell::new(
+        Flash::new_blocking(p_flash),
+    )))
+}
 
 pub struct ConfigFlash {
-    flash: Flash<'static, FLASH, Blocking, RP2040_FLASH_SIZE>,
+    flash: &'static SharedFlash,
+    /// The slot and sequence number of the config that was last loaded or written.
+    newest_slot: Option<(usize, u32)>,
+    /// The config in `CONFIG` was applied by `load_volatile_config` and has not been stored in flash.
+    volatile: bool,
 }
 
 impl ConfigFlash {
-    pub async fn new(p_flash: Peri<'static, FLASH>) -> Self {
+    pub async fn new(flash: &'static SharedFlash) -> Self {
         let mut flash = ConfigFlash {
-            flash: Flash::new_blocking(p_flash),
+            flash,
+            newest_slot: None,
+            volatile: false,
         };
         flash.load().await;
         flash
     }
 
     pub async fn load(&mut self) {
-        if let Err(()) = self.load_inner().await {
-            *CONFIG.lock().await = Some(Config::default());
+        let mut config = CONFIG.lock().await;
+        if let Err(()) = self.load_inner(&mut config) {
+            config.set(&Config::default()).unwrap();
             error!("Failed to load config from flash")
         }
+        CONFIG_CHANGED.sender().send(());
     }
 
-    async fn load_inner(&mut self) -> Result<(), ()> {
-        let bytes = self.load_config_bytes_from_flash()?;
-        let archive = rkyv::api::low::access::<ArchivedConfig, Failure>(&bytes).map_err(|_| ())?;
-        let mut config_lock = CONFIG.lock().await;
-        *config_lock = Some(rkyv::api::low::deserialize::<_, Failure>(archive).map_err(|_| ())?);
+    fn load_inner(&mut self, config: &mut ConfigBuffer) -> Result<(), ()> {
+        // The config buffer is used as scratch space to avoid placing another config sized buffer on the stack.
+        let slot = match self.find_newest_slot(&mut *config.bytes) {
+            Ok(slot) => slot,
+            Err(StoredConfigError::Empty) => {
+                info!("No config stored in flash, using default config");
+                return Err(());
+            }
+            Err(StoredConfigError::Corrupt) => {
+                error!("Every config stored in flash is corrupt");
+                return Err(());
+            }
+        };
+
+        self.read_slot(slot, &mut *config.bytes);
+        let stored = storage::read_stored_config(slot, &*config.bytes).map_err(|_| ())?;
+        let (offset, len, legacy) = (stored.offset, stored.bytes.len(), stored.legacy);
+        // Move the config to the start of the buffer, which keeps it aligned.
+        config.bytes.copy_within(offset..offset + len, 0);
+        let bytes = &config.bytes[..len];
+
+        if !legacy && storage::access_config(bytes).is_ok() {
+            config.len = len;
+        } else {
+            info!("Upgrading config stored by older firmware");
+            let upgraded = storage::decode_config(bytes).map_err(|_| ())?;
+            config.set(&upgraded)?;
+            if let Err(err) = self.write_config_bytes_to_flash(&config.bytes[..config.len]) {
+                // The upgraded config is still used, the upgrade is retried next boot.
+                error!("Failed to write upgraded config to flash {:?}", err);
+            }
+        }
 
         Ok(())
     }
 
-    pub fn load_config_bytes_from_flash(&mut self) -> Result<Align<ArrayVec<u8, CONFIG_SIZE>>, ()> {
-        // TODO: store in heap instead, apparently only 2kb of stack o.0
-        let mut bytes = [0u8; CONFIG_SIZE];
-        self.flash
-            .blocking_read(CONFIG_OFFSET as u32, &mut bytes)
-            .unwrap();
-        let size = u32::from_be_bytes(bytes[..4].try_into().unwrap());
-
-        let size = size as usize;
-        if size > CONFIG_SIZE - 4 {
-            error!("config bytes length prefix too long {}", size);
-            return Err(());
+    /// Finds the valid config slot with the highest sequence number.
+    /// Slots that fail validation, such as one whose write was interrupted by power loss, are skipped over.
+    fn find_newest_slot(&mut self, region: &mut [u8]) -> Result<usize, StoredConfigError> {
+        let mut newest = Err(StoredConfigError::Empty);
+        for slot in 0..CONFIG_SLOT_COUNT {
+            self.read_slot(slot, region);
+            match storage::read_stored_config(slot, region) {
+                Ok(stored) => {
+                    if !matches!(newest, Ok((_, sequence)) if sequence >= stored.sequence) {
+                        newest = Ok((slot, stored.sequence));
+                    }
+                }
+                Err(StoredConfigError::Corrupt) => {
+                    warn!("Config slot {} is corrupt", slot);
+                    if newest.is_err() {
+                        newest = Err(StoredConfigError::Corrupt);
+                    }
+                }
+                Err(StoredConfigError::Empty) => {}
+            }
         }
+        self.newest_slot = newest.ok();
+        newest.map(|(slot, _)| slot)
+    }
 
-        Ok(Align(ArrayVec::from_iter(
-            bytes[4..4 + size].iter().cloned(),
-        )))
+    /// `region` must be `CONFIG_SIZE` bytes long.
+    fn read_slot(&mut self, slot: usize, region: &mut [u8]) {
+        self.flash.lock(|flash| {
+            flash
+                .borrow_mut()
+                .blocking_read(config_slot_offset(slot) as u32, region)
+                .unwrap()
+        });
     }
 
-    pub fn check_valid_config(&self, bytes: &[u8]) -> Result<(), ()> {
-        let archive = rkyv::api::low::access::<ArchivedConfig, Failure>(bytes).map_err(|_| ())?;
-        rkyv::api::low::deserialize::<_, Failure>(archive).map_err(|_| ())?;
+    /// Reads the newest stored config into `bytes`, which doubles as scratch space for reading the slots
+    /// since a config sized buffer is too large for the stack.
+    pub fn load_config_bytes_from_flash(
+        &mut self,
+        bytes: &mut ArrayVec<u8, CONFIG_SIZE>,
+    ) -> Result<(), StoredConfigError> {
+        bytes.clear();
+        bytes.extend(core::iter::repeat_n(0, CONFIG_SIZE));
+        let result = self.find_newest_slot(bytes).and_then(|slot| {
+            self.read_slot(slot, bytes);
+            let stored = storage::read_stored_config(slot, bytes)?;
+            Ok((stored.offset, stored.bytes.len()))
+        });
+        match result {
+            Ok((offset, len)) => {
+                bytes.copy_within(offset..offset + len, 0);
+                bytes.truncate(len);
+                Ok(())
+            }
+            Err(err) => {
+                bytes.clear();
+                Err(err)
+            }
+        }
+    }
+
+    pub fn check_valid_config(&self, bytes: &[u8]) -> Result<(), SetConfigError> {
+        // Only the current layout is accepted, configurators are expected to upgrade configs before sending them.
+        let Ok(archive) = storage::access_config(bytes) else {
+            return Err(if storage::decode_config(bytes).is_ok() {
+                SetConfigError::VersionMismatch
+            } else {
+                SetConfigError::FailedValidation
+            });
+        };
+        rkyv::api::low::deserialize::<Config, Failure>(archive)
+            .map_err(|_| SetConfigError::FailedValidation)?;
         Ok(())
     }
 
+    /// The bytes must be aligned to at least 4 bytes.
     pub async fn load_config_bytes_to_flash_and_reload_config(
         &mut self,
-        bytes: ArrayVec<u8, CONFIG_SIZE>,
-    ) -> Result<(), ()> {
-        let size = bytes.len();
-        if size > CONFIG_SIZE {
-            error!("config bytes length prefix too long {}", size);
-            return Err(());
+        bytes: &[u8],
+    ) -> Result<(), SetConfigError> {
+        check_config_size(bytes)?;
+        self.check_valid_config(bytes)?;
+        self.write_config_bytes_to_flash(bytes).map_err(|err| {
+            error!("Failed to write config to flash {:?}", err);
+            SetConfigError::FlashWriteError
+        })?;
+
+        CONFIG.lock().await.set_validated(bytes);
+        CONFIG_CHANGED.sender().send(());
+        self.volatile = false;
+
+        Ok(())
+    }
+
+    /// Applies the config without storing it in flash, so it is lost on reboot unless `commit_volatile_config` is called.
+    /// The bytes must be aligned to at least 4 bytes.
+    pub async fn load_volatile_config(&mut self, bytes: &[u8]) -> Result<(), SetConfigError> {
+        check_config_size(bytes)?;
+        self.check_valid_config(bytes)?;
+
+        CONFIG.lock().await.set_validated(bytes);
+        CONFIG_CHANGED.sender().send(());
+        self.volatile = true;
+
+        Ok(())
+    }
+
+    /// Stores the config applied by `load_volatile_config` in flash.
+    pub async fn commit_volatile_config(&mut self) -> Result<(), SetConfigError> {
+        if !self.volatile {
+            return Err(SetConfigError::NothingToCommit);
         }
 
-        self.check_valid_config(&bytes)?;
-        // TODO: Upstream this check, blocking_erase is not sound
-        let block_aligned_size = (4 + size as u32).div_ceil(4096) * 4096;
-        self.flash
-            .blocking_erase(
-                CONFIG_OFFSET as u32,
-                CONFIG_OFFSET as u32 + block_aligned_size,
-            )
-            .unwrap();
+        let config = CONFIG.lock().await;
+        self.write_config_bytes_to_flash(config.bytes())
+            .map_err(|err| {
+                error!("Failed to write config to flash {:?}", err);
+                SetConfigError::FlashWriteError
+            })?;
+        self.volatile = false;
+
+        Ok(())
+    }
 
-        let mut final_bytes: ArrayVec<u8, CONFIG_SIZE> = ArrayVec::from_iter(size.to_be_bytes());
-        final_bytes.extend(bytes);
-        self.flash
-            .blocking_write(CONFIG_OFFSET as u32, &final_bytes)
-            .unwrap();
+    /// Reapplies the config stored in flash if a config was applied by `load_volatile_config`.
+    pub async fn revert_volatile_config(&mut self) {
+        if self.volatile {
+            self.volatile = false;
+            self.load().await;
+        }
+    }
 
-        defmt::info!("config of size {} written to flash", size.to_be_bytes());
+    /// Writes the config to the slot after the newest config, leaving the newest config intact until the write completes.
+    fn write_config_bytes_to_flash(&mut self, bytes: &[u8]) -> Result<(), flash::Error> {
+        let (slot, sequence) = match self.newest_slot {
+            Some((slot, sequence)) => ((slot + 1) % CONFIG_SLOT_COUNT, sequence + 1),
+            None => (0, 1),
+        };
+        let offset = config_slot_offset(slot) as u32;
+        self.flash.lock(|flash| {
+            let mut flash = flash.borrow_mut();
+            flash.blocking_erase(offset, offset + CONFIG_SIZE as u32)?;
 
-        self.load().await;
+            // The header is written last, so the slot only becomes valid once the config is completely written.
+            flash.blocking_write(offset + CONFIG_HEADER_SIZE as u32, bytes)?;
+            flash.blocking_write(offset, &ConfigHeader::new(bytes, sequence).to_bytes())
+        })?;
+        self.newest_slot = Some((slot, sequence));
 
+        defmt::info!(
+            "config of size {} written to flash slot {}",
+            bytes.len(),
+            slot
+        );
         Ok(())
     }
 }
+
+fn check_config_size(bytes: &[u8]) -> Result<(), SetConfigError> {
+    if bytes.len() > CONFIG_SIZE - CONFIG_HEADER_SIZE {
+        error!("config bytes too long {}", bytes.len());
+        return Err(SetConfigError::TooLarge);
+    }
+    Ok(())
+}
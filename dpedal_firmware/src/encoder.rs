@@ -0,0 +1,61 @@
+use dpedal_config::DpedalInput;
+use embassy_futures::select::select;
+use embassy_rp::gpio::Input;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+
+/// Detents from all rotary encoders, as the `DpedalInput` that the detent fires.
+pub static ENCODER_CHANNEL: Channel<ThreadModeRawMutex, DpedalInput, 16> = Channel::new();
+
+/// Indexed by `previous_state << 2 | state` where each state is `a << 1 | b`.
+/// Gives the step taken by the transition, invalid transitions where both pins changed at once are ignored.
+const QUADRATURE_STEPS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+pub struct Encoder {
+    a: Input<'static>,
+    b: Input<'static>,
+    clockwise: DpedalInput,
+    counter_clockwise: DpedalInput,
+    detent_steps: i32,
+}
+
+impl Encoder {
+    pub fn new(
+        a: Input<'static>,
+        b: Input<'static>,
+        (clockwise, counter_clockwise): (DpedalInput, DpedalInput),
+        detent_steps: u32,
+    ) -> Self {
+        Encoder {
+            a,
+            b,
+            clockwise,
+            counter_clockwise,
+            detent_steps: detent_steps as i32,
+        }
+    }
+
+    fn state(&self) -> u8 {
+        ((self.a.is_high() as u8) << 1) | self.b.is_high() as u8
+    }
+
+    pub async fn process(&mut self) {
+        let mut state = self.state();
+        let mut steps = 0;
+        loop {
+            // Both pins are interrupt driven, so we only wake up when the encoder actually moves.
+            select(self.a.wait_for_any_edge(), self.b.wait_for_any_edge()).await;
+
+            let new_state = self.state();
+            steps += QUADRATURE_STEPS[((state << 2) | new_state) as usize] as i32;
+            state = new_state;
+
+            if steps >= self.detent_steps {
+                steps = 0;
+                ENCODER_CHANNEL.send(self.clockwise).await;
+            } else if steps <= -self.detent_steps {
+                steps = 0;
+                ENCODER_CHANNEL.send(self.counter_clockwise).await;
+            }
+        }
+    }
+}
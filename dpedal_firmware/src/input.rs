@@ -1,11 +1,31 @@
-use crate::config::CONFIG;
+use crate::config::{CONFIG, CONFIG_CHANGED};
+use crate::encoder::{ENCODER_CHANNEL, Encoder};
 use crate::keyboard::{KEYBOARD_CHANNEL, KeyboardEvent};
 use crate::mouse::{MOUSE_CHANNEL, MouseEvent};
+use crate::state;
+use crate::web_config::send_input_event;
 use arrayvec::ArrayVec;
-use dpedal_config::{ComputerInput, DpedalInput, MAX_MAPPINGS};
+use core::future::pending;
+use defmt::error;
+use dpedal_config::storage::from_archived;
+use dpedal_config::web_config_protocol::InputEvent;
+use dpedal_config::{
+    ArchivedConfig, ComputerInput, DpedalInput, MAX_ROTARY_ENCODERS, RotaryEncoder,
+};
+use dpedal_engine::{Action, Engine, InputState, Millis};
+use embassy_futures::join::{join, join_array};
+use embassy_futures::select::{Either4, select, select_array, select4};
 use embassy_rp::gpio::{AnyPin, Input, Pin, Pull};
 use embassy_rp::{Peri, PeripheralType};
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
+use strum::IntoEnumIterator;
+
+/// Every `DpedalInput` can be bound to at most one pin.
+const MAX_INPUTS: usize = 6 + dpedal_config::MAX_AUX_INPUTS;
+
+/// How many actions are taken from the engine per lock of `CONFIG`.
+/// The lock is released while they are sent, so that e.g. the web configurator is not blocked waiting on the host to poll the HID reports.
+const ACTION_BATCH_SIZE: usize = 32;
 
 pub struct Inputs {
     pins: [Option<Peri<'static, AnyPin>>; 30],
@@ -17,116 +37,218 @@ impl Inputs {
     }
 
     pub async fn process(&mut self) {
-        let mut button_left_pin = 13;
-        let mut button_right_pin = 27;
-        let mut dpad_up_pin = 26;
-        let mut dpad_down_pin = 16;
-        let mut dpad_left_pin = 17;
-        let mut dpad_right_pin = 22;
-
+        let mut inputs = ArrayVec::<(DpedalInput, Input<'static>), MAX_INPUTS>::new();
+        let mut encoders: [Option<Encoder>; MAX_ROTARY_ENCODERS] =
+            [const { None }; MAX_ROTARY_ENCODERS];
         {
-            // pin_remappings cant be set by the web configurator, so we dont need to worry about resetting this after web configuration occurs.
-            let config = CONFIG.lock().await.clone().unwrap();
-            for remapping in config.pin_remappings {
-                match remapping.input {
-                    DpedalInput::DpadUp => dpad_up_pin = remapping.pin as usize,
-                    DpedalInput::DpadDown => dpad_down_pin = remapping.pin as usize,
-                    DpedalInput::DpadLeft => dpad_left_pin = remapping.pin as usize,
-                    DpedalInput::DpadRight => dpad_right_pin = remapping.pin as usize,
-                    DpedalInput::ButtonLeft => button_left_pin = remapping.pin as usize,
-                    DpedalInput::ButtonRight => button_right_pin = remapping.pin as usize,
+            // pin_remappings and rotary_encoders cant be set by the web configurator, so we dont need to worry about resetting this after web configuration occurs.
+            let config = CONFIG.lock().await;
+            let config = config.get();
+            for dpedal_input in DpedalInput::iter() {
+                let pin = config
+                    .pin_remappings
+                    .iter()
+                    .find(|remapping| {
+                        from_archived::<DpedalInput>(&remapping.input) == dpedal_input
+                    })
+                    .map(|remapping| remapping.pin.to_native())
+                    .or(dpedal_input.default_pin());
+                let Some(pin) = pin else {
+                    continue;
+                };
+                match self.take_pin(pin) {
+                    Some(pin) => inputs.push((dpedal_input, input(pin))),
+                    None => error!(
+                        "Pin {} for input {} is invalid or already in use",
+                        pin, dpedal_input
+                    ),
                 }
             }
-        }
-
-        let button_left = input(self.pins[button_left_pin].take().unwrap());
-        let button_right = input(self.pins[button_right_pin].take().unwrap());
-        let dpad_up = input(self.pins[dpad_up_pin].take().unwrap());
-        let dpad_down = input(self.pins[dpad_down_pin].take().unwrap());
-        let dpad_left = input(self.pins[dpad_left_pin].take().unwrap());
-        let dpad_right = input(self.pins[dpad_right_pin].take().unwrap());
 
-        let mut mapping_state = ArrayVec::<_, MAX_MAPPINGS>::new();
-        loop {
-            let config = CONFIG.lock().await.clone().unwrap();
-            if let Some(profile) = config.profiles.first() {
-                let input_state = DpedalInputState {
-                    button_left: button_left.is_low(),
-                    button_right: button_right.is_low(),
-                    dpad_up: dpad_up.is_low(),
-                    dpad_down: dpad_down.is_low(),
-                    dpad_left: dpad_left.is_low(),
-                    dpad_right: dpad_right.is_low(),
+            for (i, (rotary_encoder, encoder)) in
+                config.rotary_encoders.iter().zip(&mut encoders).enumerate()
+            {
+                let rotary_encoder = from_archived::<RotaryEncoder>(rotary_encoder);
+                let Some(encoder_inputs) = DpedalInput::rotary_encoder(i) else {
+                    error!("Rotary encoder {} has no inputs to map", i);
+                    continue;
+                };
+                let Some(a) = self.take_pin(rotary_encoder.pin_a) else {
+                    error!(
+                        "Pin {} for rotary encoder {} is invalid or already in use",
+                        rotary_encoder.pin_a, i
+                    );
+                    continue;
+                };
+                let Some(b) = self.take_pin(rotary_encoder.pin_b) else {
+                    // Leave pin a free for anything else that wants it.
+                    self.pins[rotary_encoder.pin_a as usize] = Some(a);
+                    error!(
+                        "Pin {} for rotary encoder {} is invalid or already in use",
+                        rotary_encoder.pin_b, i
+                    );
+                    continue;
                 };
+                *encoder = Some(Encoder::new(
+                    input(a),
+                    input(b),
+                    encoder_inputs,
+                    rotary_encoder.detent_steps(),
+                ));
+            }
+        }
 
-                // synchronize mapping_state length with any config changes.
-                mapping_state.truncate(profile.mappings.len());
-                if profile.mappings.len() > mapping_state.len() {
-                    mapping_state.push(MappingState::Released);
+        join(
+            process_mappings(inputs),
+            join_array(encoders.each_mut().map(|encoder| async move {
+                match encoder {
+                    Some(encoder) => encoder.process().await,
+                    None => pending().await,
                 }
+            })),
+        )
+        .await;
+    }
+
+    fn take_pin(&mut self, pin: u32) -> Option<Peri<'static, AnyPin>> {
+        self.pins.get_mut(pin as usize).and_then(|x| x.take())
+    }
+}
+
+async fn process_mappings(mut inputs: ArrayVec<(DpedalInput, Input<'static>), MAX_INPUTS>) {
+    let mut engine = Engine::new(state::active_profile());
+    let mut raw_inputs = InputState::default();
+    let mut config_changed = CONFIG_CHANGED.receiver().unwrap();
+    loop {
+        let deadline = engine.next_deadline();
+        let event = select4(
+            select(
+                wait_for_input_change(&mut inputs, raw_inputs),
+                wait_for_deadline(deadline),
+            ),
+            ENCODER_CHANNEL.receive(),
+            config_changed.changed(),
+            state::wait_for_profile_request(),
+        )
+        .await;
 
-                for (mapping, mapping_state) in
-                    profile.mappings.iter().zip(mapping_state.iter_mut())
-                {
-                    if input_state.is_all_pressed(&mapping.input) {
-                        for output in &mapping.output {
-                            pressed(*output).await;
-                        }
-                        *mapping_state = MappingState::Pressed;
-                    } else {
-                        for output in &mapping.output {
-                            if let MappingState::Pressed = mapping_state {
-                                released(*output).await;
-                            }
-                        }
-                        *mapping_state = MappingState::Released;
-                    }
+        if let Either4::First(_) = event {
+            let previous = raw_inputs;
+            raw_inputs = InputState::default();
+            for (dpedal_input, pin) in &inputs {
+                if pin.is_low() {
+                    raw_inputs.set_pressed(*dpedal_input);
                 }
             }
-            Timer::after_millis(1).await;
+            if raw_inputs != previous {
+                send_input_event(InputEvent::Switches(
+                    inputs
+                        .iter()
+                        .map(|(dpedal_input, _)| *dpedal_input)
+                        .filter(|dpedal_input| raw_inputs.is_pressed(*dpedal_input))
+                        .collect(),
+                ));
+            }
+        }
+        if let Either4::Second(input) = event {
+            send_input_event(InputEvent::Tapped(input));
         }
-    }
-}
 
-enum MappingState {
-    Pressed,
-    Released,
-    // TODO
-    //MacroStuff,
+        let mut actions = {
+            let config = CONFIG.lock().await;
+            let config = config.get();
+            match event {
+                Either4::First(_) => engine.update(config, raw_inputs, Instant::now().as_millis()),
+                Either4::Second(input) => engine.tap(config, input, Instant::now().as_millis()),
+                Either4::Third(()) => engine.config_changed(config),
+                Either4::Fourth(profile) => engine.set_active_profile(profile),
+            }
+            next_actions(&mut engine, config)
+        };
+        loop {
+            let batch_full = actions.is_full();
+            perform_actions(actions).await;
+            if !batch_full {
+                break;
+            }
+            actions = {
+                let config = CONFIG.lock().await;
+                let config = config.get();
+                if config_changed.try_changed().is_some() {
+                    // The config was replaced while the previous batch was sent, so the remaining actions belong to mappings that are gone.
+                    engine.config_changed(config);
+                }
+                next_actions(&mut engine, config)
+            };
+        }
+        for (profile, mapping) in engine.take_triggered_mappings() {
+            send_input_event(InputEvent::MappingTriggered {
+                profile: profile as u8,
+                mapping: mapping as u8,
+            });
+        }
+    }
 }
 
-struct DpedalInputState {
-    button_left: bool,
-    button_right: bool,
-    dpad_up: bool,
-    dpad_down: bool,
-    dpad_left: bool,
-    dpad_right: bool,
+fn next_actions(
+    engine: &mut Engine,
+    config: &ArchivedConfig,
+) -> ArrayVec<Action, ACTION_BATCH_SIZE> {
+    let mut actions = ArrayVec::new();
+    while !actions.is_full()
+        && let Some(action) = engine.next_action(config)
+    {
+        actions.push(action);
+    }
+    actions
 }
 
-impl DpedalInputState {
-    fn is_all_pressed(&self, check: &[DpedalInput]) -> bool {
-        // Disable the mapping when the inputs are entirely empty
-        // It is an obvious configuration mistake and having it constantly trigger the input would be very annoying
-        if check.is_empty() {
-            return false;
+async fn perform_actions(actions: ArrayVec<Action, ACTION_BATCH_SIZE>) {
+    for action in actions {
+        match action {
+            Action::Pressed(output) => {
+                send_input_event(InputEvent::Pressed(output));
+                pressed(output).await
+            }
+            Action::Released(output) => {
+                send_input_event(InputEvent::Released(output));
+                released(output).await
+            }
+            Action::ProfileChanged(profile) => {
+                send_input_event(InputEvent::ProfileChanged(profile as u8));
+                state::set_active_profile(profile)
+            }
+            Action::ReleaseAll => release_all().await,
         }
+    }
+}
 
-        for input in check {
-            let pressed = match input {
-                DpedalInput::DpadUp => self.dpad_up,
-                DpedalInput::DpadDown => self.dpad_down,
-                DpedalInput::DpadLeft => self.dpad_left,
-                DpedalInput::DpadRight => self.dpad_right,
-                DpedalInput::ButtonLeft => self.button_left,
-                DpedalInput::ButtonRight => self.button_right,
-            };
-
-            if !pressed {
-                return false;
+/// Waits until any input pin no longer matches `raw_inputs`.
+/// Waiting on the level rather than an edge means a change that occurred while the engine was busy is not missed.
+async fn wait_for_input_change(
+    inputs: &mut ArrayVec<(DpedalInput, Input<'static>), MAX_INPUTS>,
+    raw_inputs: InputState,
+) {
+    let mut inputs = inputs.iter_mut();
+    select_array(core::array::from_fn::<_, MAX_INPUTS, _>(|_| {
+        let input = inputs.next();
+        async move {
+            match input {
+                Some((dpedal_input, pin)) if raw_inputs.is_pressed(*dpedal_input) => {
+                    pin.wait_for_high().await
+                }
+                Some((_, pin)) => pin.wait_for_low().await,
+                None => pending().await,
             }
         }
-        true
+    }))
+    .await;
+}
+
+async fn wait_for_deadline(deadline: Option<Millis>) {
+    match deadline {
+        Some(deadline) => Timer::at(Instant::from_millis(deadline)).await,
+        None => pending().await,
     }
 }
 
@@ -148,6 +270,11 @@ async fn released(input: ComputerInput) {
     }
 }
 
+async fn release_all() {
+    KEYBOARD_CHANNEL.send(KeyboardEvent::ReleaseAll).await;
+    MOUSE_CHANNEL.send(MouseEvent::ReleaseAll).await;
+}
+
 // TODO: become Input::new
 fn input<T: PeripheralType + Pin>(pin: Peri<'static, T>) -> Input<'static> {
     let mut pin = Input::new(pin, Pull::Up);
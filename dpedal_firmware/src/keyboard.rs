@@ -1,5 +1,7 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::*;
 use dpedal_config::KeyboardInput;
+use dpedal_engine::report::KeyboardReportBuilder;
 use embassy_futures::join::join;
 use embassy_rp::{peripherals::USB, usb::Driver};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
@@ -19,6 +21,9 @@ pub struct Keyboard {
 
 pub static KEYBOARD_CHANNEL: Channel<ThreadModeRawMutex, KeyboardEvent, 64> = Channel::new();
 
+/// Set when the USB bus is reset or suspended, every key is released before any queued event is applied.
+pub static KEYBOARD_RELEASE_ALL: AtomicBool = AtomicBool::new(false);
+
 impl Keyboard {
     pub fn new(builder: &mut Builder<'static, Driver<'static, USB>>) -> Self {
         let config = embassy_usb::class::hid::Config {
@@ -51,63 +56,56 @@ impl Keyboard {
     }
 
     pub async fn process_write(&mut self) {
-        let mut report = KeyboardReport {
-            keycodes: [0, 0, 0, 0, 0, 0],
-            leds: 0,
-            modifier: 0,
-            reserved: 0,
-        };
+        let mut builder = KeyboardReportBuilder::default();
         loop {
             // Delay processing events until we are able to actually send the report to ensure the report contains the most up to date information.
             // TODO: Actually check behaviour of this await and write_serialize await, do they actually block until host has polled us?
             self.writer.ready().await;
 
+            if KEYBOARD_RELEASE_ALL.swap(false, Ordering::Relaxed) {
+                builder.release_all();
+            }
             while let Ok(event) = KEYBOARD_CHANNEL.try_receive() {
-                match event {
-                    KeyboardEvent::Pressed(key) => {
-                        set_key(&mut report, key as u8);
-                    }
-                    KeyboardEvent::Released(key) => {
-                        clear_key(&mut report, key as u8);
+                let undoes_unreported = match event {
+                    KeyboardEvent::Pressed(key) | KeyboardEvent::Released(key) => {
+                        builder.is_unreported(key)
                     }
+                    KeyboardEvent::ReleaseAll => builder.any_unreported(),
+                };
+                if undoes_unreported {
+                    // Applying the event would undo a change the host has not seen yet, e.g. a quick tap, so send that change first.
+                    self.write_report(&mut builder).await;
+                }
+                match event {
+                    KeyboardEvent::Pressed(key) => builder.pressed(key),
+                    KeyboardEvent::Released(key) => builder.released(key),
+                    KeyboardEvent::ReleaseAll => builder.release_all(),
                 };
             }
 
-            // Send the report.
-            match self.writer.write_serialize(&report).await {
-                Ok(()) => {}
-                Err(e) => warn!("Failed to send report: {:?}", e),
-            };
-        }
-    }
-}
-
-fn set_key(report: &mut KeyboardReport, keycode: u8) {
-    // if keycode already set, do nothing
-    for check_keycode in &mut report.keycodes {
-        if *check_keycode == keycode {
-            return;
-        }
-    }
-
-    // Set an empty slot to the keycode
-    for check_keycode in &mut report.keycodes {
-        if *check_keycode == 0 {
-            *check_keycode = keycode;
-            return;
+            self.write_report(&mut builder).await;
         }
     }
-}
 
-fn clear_key(report: &mut KeyboardReport, keycode: u8) {
-    for check_keycode in &mut report.keycodes {
-        if *check_keycode == keycode {
-            *check_keycode = 0;
-        }
+    async fn write_report(&mut self, builder: &mut KeyboardReportBuilder) {
+        let report = builder.next_report();
+        let report = KeyboardReport {
+            keycodes: report.keycodes,
+            leds: 0,
+            modifier: report.modifier,
+            reserved: 0,
+        };
+        match self.writer.write_serialize(&report).await {
+            Ok(()) => {}
+            Err(e) => warn!("Failed to send report: {:?}", e),
+        };
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum KeyboardEvent {
     Pressed(KeyboardInput),
     Released(KeyboardInput),
+    /// Release every key, e.g. after the config is replaced or the profile switched.
+    ReleaseAll,
 }
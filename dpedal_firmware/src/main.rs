@@ -1,19 +1,25 @@
 #![no_main]
 #![no_std]
 
+mod analog;
 mod config;
+mod encoder;
 mod input;
 mod keyboard;
 mod mouse;
+mod state;
 mod usb;
 mod web_config;
 
+use crate::analog::Analog;
 use crate::config::ConfigFlash;
 use crate::input::Inputs;
 use crate::keyboard::Keyboard;
 use crate::mouse::Mouse;
+use crate::state::StateFlash;
 use crate::web_config::WebConfig;
 use embassy_executor::Spawner;
+use embassy_futures::join::join;
 
 use {defmt_rtt as _, panic_probe as _};
 
@@ -21,7 +27,9 @@ use {defmt_rtt as _, panic_probe as _};
 async fn main(_spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
-    let config_flash = ConfigFlash::new(p.FLASH).await;
+    let flash = config::shared_flash(p.FLASH);
+    let config_flash = ConfigFlash::new(flash).await;
+    let mut state_flash = StateFlash::new(flash);
 
     let mut builder = usb::usb_builder(p.USB).await;
 
@@ -34,6 +42,12 @@ async fn main(_spawner: Spawner) {
     // Run the USB device.
     let usb_fut = usb.run();
 
+    let mut analog = Analog::new(p.ADC).await;
+    let pin_26 = analog.claim_pin(p.PIN_26);
+    let pin_27 = analog.claim_pin(p.PIN_27);
+    let pin_28 = analog.claim_pin(p.PIN_28);
+    let pin_29 = analog.claim_pin(p.PIN_29);
+
     let mut inputs = Inputs::new([
         Some(p.PIN_0.into()),
         Some(p.PIN_1.into()),
@@ -61,18 +75,18 @@ async fn main(_spawner: Spawner) {
         Some(p.PIN_23.into()),
         Some(p.PIN_24.into()),
         Some(p.PIN_25.into()),
-        Some(p.PIN_26.into()),
-        Some(p.PIN_27.into()),
-        Some(p.PIN_28.into()),
-        Some(p.PIN_29.into()),
+        pin_26,
+        pin_27,
+        pin_28,
+        pin_29,
     ]);
 
     embassy_futures::join::join5(
         usb_fut,
-        inputs.process(),
+        join(inputs.process(), analog.process()),
         keyboard.process(),
         mouse.process(),
-        web_config.process(),
+        join(web_config.process(), state_flash.process()),
     )
     .await;
 }
@@ -1,8 +1,14 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::*;
 use dpedal_config::MouseInput;
+use dpedal_engine::report::MouseReportBuilder;
 use embassy_futures::join::join;
 use embassy_rp::{peripherals::USB, usb::Driver};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+    channel::Channel,
+    signal::Signal,
+};
 use embassy_usb::{
     Builder,
     class::hid::{HidBootProtocol, HidReader, HidReaderWriter, HidSubclass, HidWriter, State},
@@ -19,6 +25,12 @@ pub struct Mouse {
 
 pub static MOUSE_CHANNEL: Channel<ThreadModeRawMutex, MouseEvent, 64> = Channel::new();
 
+/// Set when the USB bus is reset or suspended, every button is released before any queued event is applied.
+pub static MOUSE_RELEASE_ALL: AtomicBool = AtomicBool::new(false);
+
+/// Signalled once a `MouseEvent::ReleaseAll` or `MOUSE_RELEASE_ALL` has been applied, so that analog clicks still held past their threshold can be pressed again.
+pub static MOUSE_RELEASED_ALL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 impl Mouse {
     pub fn new(builder: &mut Builder<'static, Driver<'static, USB>>) -> Self {
         let config = embassy_usb::class::hid::Config {
@@ -51,97 +63,60 @@ impl Mouse {
     }
 
     pub async fn process_write(&mut self) {
-        let mut report = MouseReport {
-            buttons: 0,
-            x: 0,
-            y: 0,
-            wheel: 0,
-            pan: 0,
-        };
-
-        let mut ticks = 0u32;
-
+        let mut builder = MouseReportBuilder::default();
         loop {
-            ticks = ticks.wrapping_add(1);
             // Delay processing events until we are able to actually send the report to ensure the report contains the most up to date information.
             // TODO: Actually check behaviour of this await and write_serialize await, do they actually block until host has polled us?
             self.writer.ready().await;
 
+            if MOUSE_RELEASE_ALL.swap(false, Ordering::Relaxed) {
+                builder.release_all();
+                MOUSE_RELEASED_ALL.signal(());
+            }
             while let Ok(event) = MOUSE_CHANNEL.try_receive() {
+                let undoes_unreported = match event {
+                    MouseEvent::Pressed(input) | MouseEvent::Released(input) => {
+                        builder.is_unreported(input)
+                    }
+                    MouseEvent::ReleaseAll => builder.any_unreported(),
+                    MouseEvent::Analog { .. } => false,
+                };
+                if undoes_unreported {
+                    // Applying the event would undo a change the host has not seen yet, e.g. a quick click, so send that change first.
+                    self.write_report(&mut builder).await;
+                }
                 match event {
-                    MouseEvent::Pressed(input) => match input {
-                        MouseInput::ScrollUp(value) => {
-                            scroll(&mut report, ticks, 0, (value / 10) as i8)
-                        }
-                        MouseInput::ScrollDown(value) => {
-                            scroll(&mut report, ticks, 0, (value / -10) as i8)
-                        }
-                        MouseInput::ScrollLeft(value) => {
-                            scroll(&mut report, ticks, (value / -10) as i8, 0)
-                        }
-                        MouseInput::ScrollRight(value) => {
-                            scroll(&mut report, ticks, (value / 10) as i8, 0)
-                        }
-                        MouseInput::MoveUp(value) => {
-                            move_cursor(&mut report, ticks, 0, (value / -10) as i8)
-                        }
-                        MouseInput::MoveDown(value) => {
-                            move_cursor(&mut report, ticks, 0, (value / 10) as i8)
-                        }
-                        MouseInput::MoveLeft(value) => {
-                            move_cursor(&mut report, ticks, (value / -10) as i8, 0)
-                        }
-                        MouseInput::MoveRight(value) => {
-                            move_cursor(&mut report, ticks, (value / 10) as i8, 0)
-                        }
-                        MouseInput::ClickLeft => report.buttons |= 0b0000_0001,
-                        MouseInput::ClickRight => report.buttons |= 0b0000_0010,
-                        MouseInput::ClickMiddle => report.buttons |= 0b0000_0100,
-                    },
-                    MouseEvent::Released(input) => match input {
-                        MouseInput::ScrollUp(_)
-                        | MouseInput::ScrollDown(_)
-                        | MouseInput::ScrollLeft(_)
-                        | MouseInput::ScrollRight(_)
-                        | MouseInput::MoveUp(_)
-                        | MouseInput::MoveDown(_)
-                        | MouseInput::MoveLeft(_)
-                        | MouseInput::MoveRight(_) => {
-                            // Releasing one of these inputs has no effect
-                        }
-                        MouseInput::ClickLeft => report.buttons &= 0b1111_1110,
-                        MouseInput::ClickRight => report.buttons &= 0b1111_1101,
-                        MouseInput::ClickMiddle => report.buttons &= 0b1111_1011,
-                    },
+                    MouseEvent::Pressed(input) => builder.pressed(input),
+                    MouseEvent::Released(input) => builder.released(input),
+                    MouseEvent::ReleaseAll => {
+                        builder.release_all();
+                        MOUSE_RELEASED_ALL.signal(());
+                    }
+                    MouseEvent::Analog {
+                        index,
+                        input,
+                        amount,
+                    } => builder.analog(index, input, amount),
                 }
             }
 
-            // Send the report.
-            match self.writer.write_serialize(&report).await {
-                Ok(()) => {}
-                Err(e) => warn!("Failed to send report: {:?}", e),
-            };
-
-            // reset non-button inputs
-            report.wheel = 0;
-            report.x = 0;
-            report.y = 0;
-            report.pan = 0;
+            self.write_report(&mut builder).await;
         }
     }
-}
 
-fn scroll(report: &mut MouseReport, ticks: u32, x: i8, y: i8) {
-    if ticks.is_multiple_of(80) {
-        report.pan += x;
-        report.wheel += y;
-    }
-}
-
-fn move_cursor(report: &mut MouseReport, ticks: u32, x: i8, y: i8) {
-    if ticks.is_multiple_of(80) {
-        report.x += x;
-        report.y += y;
+    async fn write_report(&mut self, builder: &mut MouseReportBuilder) {
+        let report = builder.next_report();
+        let report = MouseReport {
+            buttons: report.buttons,
+            x: report.x,
+            y: report.y,
+            wheel: report.wheel,
+            pan: report.pan,
+        };
+        match self.writer.write_serialize(&report).await {
+            Ok(()) => {}
+            Err(e) => warn!("Failed to send report: {:?}", e),
+        };
     }
 }
 
@@ -150,4 +125,12 @@ fn move_cursor(report: &mut MouseReport, ticks: u32, x: i8, y: i8) {
 pub enum MouseEvent {
     Pressed(MouseInput),
     Released(MouseInput),
+    /// Release every button and stop every held scroll and move input, e.g. after the config is replaced or the profile switched.
+    ReleaseAll,
+    /// Continuously drive a scroll or move input, amount is in thousandths of full travel.
+    Analog {
+        index: u8,
+        input: MouseInput,
+        amount: u16,
+    },
 }
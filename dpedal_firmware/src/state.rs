@@ -0,0 +1,104 @@
+use crate::config::SharedFlash;
+use core::sync::atomic::{AtomicU8, Ordering};
+use defmt::info;
+use dpedal_config::state::{
+    self, RuntimeState, STATE_RECORD_SIZE, STATE_RECORDS_PER_SECTOR, STATE_SECTOR_SIZE,
+    StateRecord, state_record_offset,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, with_timeout};
+
+/// Index into `Config::profiles` of the active profile, mirrored from the input engine so it can be persisted.
+static ACTIVE_PROFILE: AtomicU8 = AtomicU8::new(0);
+static STATE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static PROFILE_REQUESTED: Signal<CriticalSectionRawMutex, usize> = Signal::new();
+
+/// State is only written once it has stopped changing for this long,
+/// so quickly cycling through profiles results in a single flash write.
+const WRITE_DELAY: Duration = Duration::from_secs(5);
+
+pub fn active_profile() -> usize {
+    ACTIVE_PROFILE.load(Ordering::Relaxed) as usize
+}
+
+pub fn set_active_profile(profile: usize) {
+    ACTIVE_PROFILE.store(profile as u8, Ordering::Relaxed);
+    STATE_CHANGED.signal(());
+}
+
+/// Asks the input engine to switch to `profile`, which it does once every output of the current profile has been released.
+pub fn request_active_profile(profile: usize) {
+    PROFILE_REQUESTED.signal(profile);
+}
+
+/// Waits for the next `request_active_profile`, only the most recent request is returned.
+pub async fn wait_for_profile_request() -> usize {
+    PROFILE_REQUESTED.wait().await
+}
+
+pub struct StateFlash {
+    flash: &'static SharedFlash,
+    /// The index and contents of the most recently written record.
+    latest: Option<(usize, StateRecord)>,
+}
+
+impl StateFlash {
+    pub fn new(flash: &'static SharedFlash) -> Self {
+        let latest = state::find_latest_record(|index| {
+            let mut bytes = [0; STATE_RECORD_SIZE];
+            flash.lock(|flash| {
+                flash
+                    .borrow_mut()
+                    .blocking_read(state_record_offset(index) as u32, &mut bytes)
+                    .unwrap()
+            });
+            bytes
+        });
+        if let Some((_, record)) = latest {
+            ACTIVE_PROFILE.store(record.state.active_profile, Ordering::Relaxed);
+        }
+        StateFlash { flash, latest }
+    }
+
+    pub async fn process(&mut self) {
+        loop {
+            STATE_CHANGED.wait().await;
+            // Keep waiting until the state has settled.
+            while with_timeout(WRITE_DELAY, STATE_CHANGED.wait())
+                .await
+                .is_ok()
+            {}
+
+            let state = RuntimeState {
+                active_profile: ACTIVE_PROFILE.load(Ordering::Relaxed),
+            };
+            if self.latest.map(|(_, record)| record.state) != Some(state) {
+                self.write(state);
+            }
+        }
+    }
+
+    fn write(&mut self, state: RuntimeState) {
+        let index = state::next_record_index(self.latest.map(|(index, _)| index));
+        let record = StateRecord {
+            sequence: self
+                .latest
+                .map(|(_, record)| record.sequence + 1)
+                .unwrap_or(0),
+            state,
+        };
+        let offset = state_record_offset(index) as u32;
+        self.flash.lock(|flash| {
+            let mut flash = flash.borrow_mut();
+            if index.is_multiple_of(STATE_RECORDS_PER_SECTOR) {
+                flash
+                    .blocking_erase(offset, offset + STATE_SECTOR_SIZE as u32)
+                    .unwrap();
+            }
+            flash.blocking_write(offset, &record.to_bytes()).unwrap();
+        });
+        self.latest = Some((index, record));
+
+        info!("runtime state {} written to flash", state);
+    }
+}
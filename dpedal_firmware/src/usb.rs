@@ -10,6 +10,8 @@ use embassy_usb::{Builder, Config, Handler};
 use static_cell::StaticCell;
 
 use crate::config::CONFIG;
+use crate::keyboard::KEYBOARD_RELEASE_ALL;
+use crate::mouse::MOUSE_RELEASE_ALL;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
@@ -30,11 +32,13 @@ pub async fn usb_builder(usb: Peri<'static, USB>) -> Builder<'static, Driver<'st
     let mut config = Config::new(0xc0de, 0xcafe);
     config.manufacturer = Some("Rukai");
     let product = PRODUCT_NAME.init(ArrayString::from("DPedal").unwrap());
-    let device_config = CONFIG.lock().await.clone().unwrap();
-    if !device_config.nickname.is_empty() {
+    let device_config = CONFIG.lock().await;
+    let nickname = &device_config.get().nickname;
+    if !nickname.is_empty() {
         product.push_str(" - ");
-        product.push_str(&device_config.nickname);
+        product.push_str(nickname);
     }
+    drop(device_config);
     match env!("PROFILE") {
         "release" => {}
         _ => product.push_str(" (debug build)"),
@@ -105,6 +109,16 @@ impl Handler for MyDeviceHandler {
     fn reset(&mut self) {
         self.configured.store(false, Ordering::Relaxed);
         info!("Bus reset, the Vbus current limit is 100mA");
+        release_all();
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        if suspended {
+            info!("Device suspended");
+            release_all();
+        } else {
+            info!("Device resumed");
+        }
     }
 
     fn addressed(&mut self, addr: u8) {
@@ -123,3 +137,12 @@ impl Handler for MyDeviceHandler {
         }
     }
 }
+
+/// The host forgets which keys and buttons are held when the bus is reset or suspended,
+/// so release them all to avoid them appearing stuck down once it starts polling again.
+///
+/// A flag is used rather than the event channels, which fill up while the host is not polling and would drop the release.
+fn release_all() {
+    KEYBOARD_RELEASE_ALL.store(true, Ordering::Relaxed);
+    MOUSE_RELEASE_ALL.store(true, Ordering::Relaxed);
+}
@@ -1,17 +1,30 @@
+use arrayvec::{ArrayString, ArrayVec};
+use core::future::pending;
+use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::*;
-use dpedal_config::web_config_protocol::{Request, Response};
+use dpedal_config::storage::from_archived;
+use dpedal_config::web_config_protocol::{
+    BuildProfile, CONFIG_CHUNK_SIZE, DeviceInfo, Handshake, InputEvent, MAX_MESSAGE_SIZE,
+    OutputKind, PROTOCOL_VERSION, Request, Response, SetActiveProfileError,
+    VOLATILE_CONFIG_TIMEOUT_MS,
+};
+use dpedal_config::{CONFIG_SIZE, RP2040_FLASH_SIZE};
+use embassy_futures::select::{Either3, select3};
 use embassy_rp::usb::{Endpoint, In, Out};
 use embassy_rp::{peripherals::USB, usb::Driver};
-//use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::Builder;
 use embassy_usb::class::web_usb::{Config as WebUsbConfig, State, WebUsb};
-use embassy_usb::driver::{Endpoint as EndpointTrait, EndpointIn, EndpointOut};
+use embassy_usb::driver::{Endpoint as EndpointTrait, EndpointError, EndpointIn, EndpointOut};
 use embassy_usb::msos::{self, windows_version};
 use embassy_usb::types::InterfaceNumber;
 use postcard::accumulator::CobsAccumulator;
+use rkyv::util::Align;
 use static_cell::StaticCell;
 
-use crate::config::ConfigFlash;
+use crate::config::{CONFIG, ConfigFlash};
+use crate::state;
 
 // This is a randomly generated GUID to allow clients on Windows to find our device
 const DEVICE_INTERFACE_GUIDS: &[&str] = &["{da327103-02a8-4d8a-8329-be81cdb97cc7}"];
@@ -20,10 +33,28 @@ pub struct WebConfig {
     write_ep: Endpoint<'static, USB, In>,
     read_ep: Endpoint<'static, USB, Out>,
     config_flash: ConfigFlash,
+    /// The config being downloaded by `Request::GetConfigChunk` or uploaded by `Request::SetConfigChunk`.
+    transfer: Align<ArrayVec<u8, CONFIG_SIZE>>,
+    /// Set once the configurator has sent a compatible `Request::Handshake`.
+    handshake_complete: bool,
+    /// When a config applied by `Request::SetConfigVolatile` is reverted, unless another request is received first.
+    volatile_deadline: Option<Instant>,
 }
 
 //pub static CONFIG_CHANNEL: Channel<ThreadModeRawMutex, (), 64> = Channel::new();
 
+static INPUT_EVENT_CHANNEL: Channel<ThreadModeRawMutex, InputEvent, 32> = Channel::new();
+/// Set while the web configurator is subscribed to input events.
+static INPUT_EVENTS_SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+/// Streams `event` to the web configurator if it is subscribed.
+/// Never waits, the event is dropped instead if the web configurator is not keeping up, so that inputs are never delayed.
+pub fn send_input_event(event: InputEvent) {
+    if INPUT_EVENTS_SUBSCRIBED.load(Ordering::Relaxed) {
+        let _ = INPUT_EVENT_CHANNEL.try_send(event);
+    }
+}
+
 impl WebConfig {
     pub fn new(
         builder: &mut Builder<'static, Driver<'static, USB>>,
@@ -72,13 +103,24 @@ impl WebConfig {
             write_ep,
             read_ep,
             config_flash,
+            transfer: Align(ArrayVec::new()),
+            handshake_complete: false,
+            volatile_deadline: None,
         }
     }
 
     pub async fn process(&mut self) {
-        self.wait_connected().await;
-        info!("Connected to web configurator");
-        self.echo().await;
+        loop {
+            self.wait_connected().await;
+            info!("Connected to web configurator");
+            let _ = self.echo().await;
+            info!("Disconnected from web configurator");
+
+            self.handshake_complete = false;
+            INPUT_EVENTS_SUBSCRIBED.store(false, Ordering::Relaxed);
+            INPUT_EVENT_CHANNEL.clear();
+            self.revert_volatile_config().await;
+        }
     }
 
     // Wait until the device's endpoints are enabled.
@@ -86,60 +128,237 @@ impl WebConfig {
         self.read_ep.wait_enabled().await
     }
 
-    // Echo data back to the host.
-    async fn echo(&mut self) {
+    // Echo data back to the host, until the device is disconnected.
+    async fn echo(&mut self) -> Result<(), EndpointError> {
         let mut packet_buf = [0; 64];
         'skip_request: loop {
-            let mut cobs_buf: CobsAccumulator<1024> = CobsAccumulator::new();
+            let mut cobs_buf: CobsAccumulator<MAX_MESSAGE_SIZE> = CobsAccumulator::new();
             let request = loop {
-                let n = self.read_ep.read(&mut packet_buf).await.unwrap();
+                let n = match select3(
+                    self.read_ep.read(&mut packet_buf),
+                    INPUT_EVENT_CHANNEL.receive(),
+                    wait_until(self.volatile_deadline),
+                )
+                .await
+                {
+                    Either3::First(n) => n?,
+                    Either3::Second(event) => {
+                        self.send_response(Response::InputEvent(event)).await?;
+                        continue;
+                    }
+                    Either3::Third(()) => {
+                        warn!("Configurator stopped responding, reverting volatile config");
+                        self.revert_volatile_config().await;
+                        continue;
+                    }
+                };
                 match cobs_buf.feed::<Request>(&packet_buf[..n]) {
                     postcard::accumulator::FeedResult::Consumed => {}
                     postcard::accumulator::FeedResult::OverFull(_items) => {
-                        error!("request exceeded 1024 bytes");
-                        self.send_response(Response::ProtocolError).await;
+                        error!("request exceeded {} bytes", MAX_MESSAGE_SIZE);
+                        self.send_response(Response::ProtocolError).await?;
                         continue 'skip_request;
                     }
                     postcard::accumulator::FeedResult::DeserError(_items) => {
                         error!("Failed to deserialize request");
-                        self.send_response(Response::ProtocolError).await;
+                        self.send_response(Response::ProtocolError).await?;
                         continue 'skip_request;
                     }
                     postcard::accumulator::FeedResult::Success { data, .. } => break data,
                 }
             };
+            if self.volatile_deadline.is_some() {
+                self.volatile_deadline =
+                    Some(Instant::now() + Duration::from_millis(VOLATILE_CONFIG_TIMEOUT_MS));
+            }
             let response = match request {
-                Request::GetConfig => Response::GetConfig(
-                    self.config_flash
-                        .load_config_bytes_from_flash()
-                        .map(|x| x.0),
-                ),
-                Request::SetConfig(array_vec) => {
-                    defmt::info!("set config {:?}", array_vec.as_ref());
-                    if let Err(()) = self
-                        .config_flash
-                        .load_config_bytes_to_flash_and_reload_config(array_vec)
-                        .await
-                    {
-                        // TODO: return error over protocol
-                        defmt::panic!("Config invalid, not writing to flash")
+                Request::Handshake(handshake) => {
+                    // A new configurator does not know about a config that the previous one was trying.
+                    self.revert_volatile_config().await;
+                    self.handshake_complete = handshake.is_compatible();
+                    if !self.handshake_complete {
+                        warn!(
+                            "Configurator speaks protocol version {} with schema {:x}, but the firmware speaks version {} with schema {:x}",
+                            handshake.protocol_version,
+                            handshake.schema_hash,
+                            Handshake::CURRENT.protocol_version,
+                            Handshake::CURRENT.schema_hash
+                        );
+                    }
+                    Response::Handshake(Handshake::CURRENT)
+                }
+                Request::GetDeviceInfo => Response::GetDeviceInfo(device_info().await),
+                _ if !self.handshake_complete => {
+                    error!("Request refused since no compatible handshake has been made");
+                    Response::ProtocolError
+                }
+                Request::GetConfig => match self
+                    .config_flash
+                    .load_config_bytes_from_flash(&mut self.transfer)
+                {
+                    Ok(()) => Response::GetConfig(Ok(self.transfer.len() as u32)),
+                    Err(err) => Response::GetConfig(Err(err)),
+                },
+                Request::GetConfigChunk { offset } => match self.transfer.get(offset as usize..) {
+                    Some(rest) => Response::GetConfigChunk(ArrayVec::from_iter(
+                        rest.iter().take(CONFIG_CHUNK_SIZE).cloned(),
+                    )),
+                    None => {
+                        error!("config chunk offset {} is out of range", offset);
+                        Response::ProtocolError
+                    }
+                },
+                Request::SetConfigChunk { offset, bytes } => {
+                    if offset == 0 {
+                        self.transfer.clear();
+                    }
+                    if offset as usize != self.transfer.len() {
+                        error!(
+                            "config chunk offset {} does not follow the previous chunk ending at {}",
+                            offset,
+                            self.transfer.len()
+                        );
+                        Response::ProtocolError
+                    } else if self.transfer.try_extend_from_slice(&bytes).is_err() {
+                        error!("config chunk exceeds {} bytes", CONFIG_SIZE);
+                        Response::ProtocolError
+                    } else {
+                        Response::SetConfigChunk
+                    }
+                }
+                Request::Subscribe => {
+                    INPUT_EVENTS_SUBSCRIBED.store(true, Ordering::Relaxed);
+                    Response::Subscribe
+                }
+                Request::Unsubscribe => {
+                    INPUT_EVENTS_SUBSCRIBED.store(false, Ordering::Relaxed);
+                    INPUT_EVENT_CHANNEL.clear();
+                    Response::Unsubscribe
+                }
+                Request::SetConfig { len } => {
+                    if len as usize != self.transfer.len() {
+                        error!(
+                            "config is {} bytes but only {} bytes were uploaded",
+                            len,
+                            self.transfer.len()
+                        );
+                        Response::ProtocolError
+                    } else {
+                        defmt::info!("set config {:?}", self.transfer.as_slice());
+                        let result = self
+                            .config_flash
+                            .load_config_bytes_to_flash_and_reload_config(&self.transfer)
+                            .await;
+                        match result {
+                            // The stored config replaces any volatile config.
+                            Ok(()) => self.volatile_deadline = None,
+                            Err(err) => error!("Config rejected, not writing to flash {:?}", err),
+                        }
+                        Response::SetConfig(result)
+                    }
+                }
+                Request::SetConfigVolatile { len } => {
+                    if len as usize != self.transfer.len() {
+                        error!(
+                            "config is {} bytes but only {} bytes were uploaded",
+                            len,
+                            self.transfer.len()
+                        );
+                        Response::ProtocolError
+                    } else {
+                        let result = self.config_flash.load_volatile_config(&self.transfer).await;
+                        match result {
+                            Ok(()) => {
+                                self.volatile_deadline = Some(
+                                    Instant::now()
+                                        + Duration::from_millis(VOLATILE_CONFIG_TIMEOUT_MS),
+                                )
+                            }
+                            Err(err) => error!("Volatile config rejected {:?}", err),
+                        }
+                        Response::SetConfigVolatile(result)
+                    }
+                }
+                Request::CommitConfig => {
+                    let result = self.config_flash.commit_volatile_config().await;
+                    if result.is_ok() {
+                        self.volatile_deadline = None;
+                    }
+                    Response::CommitConfig(result)
+                }
+                Request::RevertConfig => {
+                    self.revert_volatile_config().await;
+                    Response::RevertConfig
+                }
+                Request::KeepAlive => Response::KeepAlive,
+                Request::GetActiveProfile => {
+                    Response::GetActiveProfile(state::active_profile() as u8)
+                }
+                Request::SetActiveProfile(profile) => {
+                    if (profile as usize) < CONFIG.lock().await.get().profiles.len() {
+                        state::request_active_profile(profile as usize);
+                        Response::SetActiveProfile(Ok(()))
+                    } else {
+                        error!("Requested profile {} does not exist", profile);
+                        Response::SetActiveProfile(Err(SetActiveProfileError::DoesNotExist))
                     }
-                    Response::SetConfig
                 }
             };
 
-            self.send_response(response).await;
+            self.send_response(response).await?;
         }
     }
 
-    async fn send_response(&mut self, response: Response) {
-        let mut response_buf = [0; 1024];
+    async fn revert_volatile_config(&mut self) {
+        self.volatile_deadline = None;
+        self.config_flash.revert_volatile_config().await;
+    }
+
+    async fn send_response(&mut self, response: Response) -> Result<(), EndpointError> {
+        let mut response_buf = [0; MAX_MESSAGE_SIZE];
         let response = postcard::to_slice_cobs(&response, &mut response_buf).unwrap();
         info!("responsed with message containing {} bytes", response.len());
         for chunk in response.chunks(64) {
             if !chunk.is_empty() {
-                self.write_ep.write(chunk).await.unwrap();
+                self.write_ep.write(chunk).await?;
             }
         }
+        Ok(())
+    }
+}
+
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => Timer::at(deadline).await,
+        None => pending().await,
+    }
+}
+
+/// Build time strings are cut short rather than panicking if they do not fit.
+fn truncated<const CAP: usize>(value: &str) -> ArrayString<CAP> {
+    let mut result = ArrayString::new();
+    for c in value.chars() {
+        if result.try_push(c).is_err() {
+            break;
+        }
+    }
+    result
+}
+
+async fn device_info() -> DeviceInfo {
+    let device = from_archived(&CONFIG.lock().await.get().device);
+    DeviceInfo {
+        firmware_version: truncated(env!("CARGO_PKG_VERSION")),
+        build_profile: match env!("PROFILE") {
+            "release" => BuildProfile::Release,
+            _ => BuildProfile::Debug,
+        },
+        git_hash: truncated(env!("GIT_HASH")),
+        device,
+        protocol_version: PROTOCOL_VERSION,
+        flash_size: RP2040_FLASH_SIZE as u32,
+        supported_outputs: [OutputKind::Mouse, OutputKind::Keyboard, OutputKind::Control]
+            .into_iter()
+            .collect(),
     }
 }
@@ -1,6 +1,10 @@
 use std::{env, process::Command};
 
 fn main() {
+    if env::var_os("CARGO_FEATURE_FIRMWARE").is_none() {
+        return;
+    }
+
     for file in std::fs::read_dir("../dpedal_firmware").unwrap() {
         let path = file.unwrap().path();
 
@@ -39,4 +43,13 @@ fn main() {
         format!("../../dpedal_firmware/target/thumbv6m-none-eabi/{profile}/dpedal_firmware");
 
     println!("cargo:rustc-env=FIRMWARE_PATH={firmware_path}");
+
+    // The version that the embedded firmware reports in its `DeviceInfo`.
+    let manifest = std::fs::read_to_string(format!("{firmware_dir}/Cargo.toml")).unwrap();
+    let firmware_version = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("version = "))
+        .unwrap()
+        .trim_matches('"');
+    println!("cargo:rustc-env=FIRMWARE_VERSION={firmware_version}");
 }
@@ -12,4 +12,7 @@ pub struct Args {
     /// This is only useful for development purposes, for testing invalid config.
     #[arg(long)]
     pub erase_config: bool,
+    /// Flash even if the device is already running the same firmware.
+    #[arg(long)]
+    pub force: bool,
 }
@@ -1,5 +1,9 @@
 use arrayvec::{ArrayString, ArrayVec};
-use dpedal_config::{ComputerInput, Config, DpedalInput, KeyboardInput, MouseInput};
+use dpedal_config::{
+    CONFIG_VERSION, ComputerInput, Config, DPedalControl, DpedalInput, KeyboardInput,
+    MAX_ANALOG_INPUTS, MAX_MAPPING_INPUTS, MAX_PIN_REMAPPINGS, MAX_RELEASE_OUTPUTS,
+    MAX_ROTARY_ENCODERS, MouseInput, Trigger, storage::ConfigHeader,
+};
 use kdl::{KdlDocument, KdlNode};
 use kdl_config::{
     KdlConfig, KdlConfigFinalize, Parsed,
@@ -13,7 +17,8 @@ use std::{path::PathBuf, str::FromStr};
 pub fn encode_config(config: &Config) -> miette::Result<Vec<u8>> {
     let bytes = rkyv::to_bytes::<Error>(config).map_err(|e| miette!(e))?;
     let mut result = vec![];
-    result.extend((bytes.len() as u32).to_be_bytes());
+    // Written to the first slot with the first sequence number, see `crate::flash::flash_device`.
+    result.extend(ConfigHeader::new(&bytes, 1).to_bytes());
     result.extend(bytes.iter());
     Ok(result)
 }
@@ -30,7 +35,18 @@ pub fn load(path: Option<PathBuf>) -> miette::Result<Config> {
         return Err(error.into());
     }
 
-    Ok(profile.value.finalize())
+    let mut config = profile.value.finalize();
+    if config.version > CONFIG_VERSION {
+        return Err(miette!(
+            "Config is version {} but this version of dpedal_flash only supports up to version {}, please update dpedal_flash.",
+            config.version,
+            CONFIG_VERSION
+        ));
+    }
+    // Older KDL configs are still parsed correctly, they just need to be stored in the current layout.
+    config.version = CONFIG_VERSION;
+
+    Ok(config)
 }
 
 fn load_source(path: Option<PathBuf>) -> miette::Result<NamedSource<String>> {
@@ -64,7 +80,12 @@ pub struct ConfigKdl {
     pub color: Parsed<u32>,
     pub profiles: Parsed<ArrayVec<Parsed<ProfileKdl>, 2>>,
     // TODO: add validation: no duplicate pins (including default values), valid pin range
-    pub pin_remappings: Parsed<ArrayVec<Parsed<PinRemappingKdl>, 6>>,
+    pub pin_remappings: Parsed<ArrayVec<Parsed<PinRemappingKdl>, MAX_PIN_REMAPPINGS>>,
+    pub analog_inputs: Parsed<ArrayVec<Parsed<AnalogInputKdl>, MAX_ANALOG_INPUTS>>,
+    pub rotary_encoders: Parsed<ArrayVec<Parsed<RotaryEncoderKdl>, MAX_ROTARY_ENCODERS>>,
+    pub max_hold_ms: Parsed<u32>,
+    pub combo_window_ms: Parsed<u32>,
+    pub sequence_timeout_ms: Parsed<u32>,
 }
 
 #[derive(KdlConfig, KdlConfigFinalize, Default, Debug)]
@@ -74,6 +95,92 @@ pub struct PinRemappingKdl {
     pub pin: Parsed<u32>,
 }
 
+#[derive(KdlConfig, KdlConfigFinalize, Default, Debug)]
+#[kdl_config_finalize_into = "dpedal_config::RotaryEncoder"]
+pub struct RotaryEncoderKdl {
+    pub pin_a: Parsed<u32>,
+    pub pin_b: Parsed<u32>,
+    pub steps_per_detent: Parsed<u32>,
+}
+
+#[derive(KdlConfig, KdlConfigFinalize, Default, Debug)]
+#[kdl_config_finalize_into = "dpedal_config::AnalogInput"]
+pub struct AnalogInputKdl {
+    pub pin: Parsed<u32>,
+    pub min: Parsed<u32>,
+    pub max: Parsed<u32>,
+    pub deadzone: Parsed<u32>,
+    pub curve: Parsed<AnalogCurveKdl>,
+    pub output: Parsed<AnalogOutputKdl>,
+}
+
+#[derive(KdlConfig, KdlConfigFinalize, Default, Debug)]
+#[kdl_config_finalize_into = "dpedal_config::AnalogCurve"]
+pub enum AnalogCurveKdl {
+    #[default]
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+#[derive(Default, Debug)]
+pub struct AnalogOutputKdl {
+    pub output: dpedal_config::AnalogOutput,
+}
+
+impl KdlConfigFinalize for AnalogOutputKdl {
+    type FinalizeType = dpedal_config::AnalogOutput;
+
+    fn finalize(&self) -> Self::FinalizeType {
+        self.output
+    }
+}
+
+impl KdlConfig for AnalogOutputKdl {
+    fn parse_as_node(
+        source: NamedSource<String>,
+        node: &KdlNode,
+        diagnostics: &mut Vec<kdl_config::error::ParseDiagnostic>,
+    ) -> Parsed<Self>
+    where
+        Self: Sized,
+    {
+        let output = match node.entries().first().map(|x| x.value()) {
+            Some(kdl::KdlValue::String(value)) => parse_output(value.trim()),
+            _ => None,
+        };
+        match output {
+            Some(ComputerInput::Mouse(input)) => Parsed {
+                value: AnalogOutputKdl {
+                    output: dpedal_config::AnalogOutput::Mouse(input),
+                },
+                full_span: node.span(),
+                name_span: node.span(),
+                valid: true,
+            },
+            _ => {
+                diagnostics.push(ParseDiagnostic {
+                    input: source.clone(),
+                    span: node.span(),
+                    message: Some(
+                        "Analog output must be a mouse output such as \"mouse-scroll-up 20\""
+                            .to_owned(),
+                    ),
+                    label: None,
+                    help: None,
+                    severity: miette::Severity::Error,
+                });
+                Parsed {
+                    value: Default::default(),
+                    full_span: node.span(),
+                    name_span: node.span(),
+                    valid: false,
+                }
+            }
+        }
+    }
+}
+
 // TODO: add derive side validation that Parsed is used everywhere.
 #[derive(KdlConfig, KdlConfigFinalize, Default, Debug)]
 #[kdl_config_finalize_into = "dpedal_config::Profile"]
@@ -83,8 +190,10 @@ pub struct ProfileKdl {
 
 #[derive(Default, Debug)]
 pub struct MappingKdl {
-    pub input: ArrayVec<dpedal_config::DpedalInput, 4>,
+    pub trigger: Trigger,
+    pub input: ArrayVec<dpedal_config::DpedalInput, MAX_MAPPING_INPUTS>,
     pub output: ArrayVec<dpedal_config::ComputerInput, 20>,
+    pub release_output: ArrayVec<dpedal_config::ComputerInput, MAX_RELEASE_OUTPUTS>,
 }
 
 impl KdlConfigFinalize for MappingKdl {
@@ -92,8 +201,10 @@ impl KdlConfigFinalize for MappingKdl {
 
     fn finalize(&self) -> Self::FinalizeType {
         Self::FinalizeType {
+            trigger: self.trigger,
             input: self.input.clone(),
             output: self.output.clone(),
+            release_output: self.release_output.clone(),
         }
     }
 }
@@ -147,25 +258,52 @@ impl KdlConfig for MappingKdl {
                 };
                 let output = output.trim();
 
-                let Some(input) = DpedalInput::from_string_kebab(input) else {
-                    diagnostics.push(ParseDiagnostic {
-                        input: source.clone(),
-                        span: node.span(),
-                        message: Some(format!("Unknown input {input:?}")),
-                        label: None,
-                        help: None,
-                        severity: miette::Severity::Error,
-                    });
-                    return Parsed {
-                        value: Default::default(),
-                        full_span: node.span(),
-                        name_span: node.span(),
-                        valid: false,
-                    };
+                // `a+b` is a chord of inputs held together, `a, b` is a sequence of inputs pressed one after another.
+                let (trigger, separator) = if input.contains(',') {
+                    (Trigger::Sequence, ',')
+                } else {
+                    (Trigger::Chord, '+')
                 };
-                let input = ArrayVec::from_iter([input]);
+                let mut inputs: ArrayVec<DpedalInput, MAX_MAPPING_INPUTS> = ArrayVec::new();
+                for input in input.split(separator).map(|x| x.trim()) {
+                    let Some(input) = DpedalInput::from_string_kebab(input) else {
+                        diagnostics.push(ParseDiagnostic {
+                            input: source.clone(),
+                            span: node.span(),
+                            message: Some(format!("Unknown input {input:?}")),
+                            label: None,
+                            help: None,
+                            severity: miette::Severity::Error,
+                        });
+                        return Parsed {
+                            value: Default::default(),
+                            full_span: node.span(),
+                            name_span: node.span(),
+                            valid: false,
+                        };
+                    };
+                    if inputs.try_push(input).is_err() {
+                        diagnostics.push(ParseDiagnostic {
+                            input: source.clone(),
+                            span: node.span(),
+                            message: Some(format!(
+                                "A mapping can combine at most {} inputs",
+                                inputs.capacity()
+                            )),
+                            label: None,
+                            help: None,
+                            severity: miette::Severity::Error,
+                        });
+                        return Parsed {
+                            value: Default::default(),
+                            full_span: node.span(),
+                            name_span: node.span(),
+                            valid: false,
+                        };
+                    }
+                }
 
-                let Some((ty, sub_ty)) = output.split_once("-") else {
+                let Some(output) = parse_output(output) else {
                     diagnostics.push(ParseDiagnostic {
                         input: source.clone(),
                         span: node.span(),
@@ -181,51 +319,16 @@ impl KdlConfig for MappingKdl {
                         valid: false,
                     };
                 };
+                let output = ArrayVec::from_iter([output]);
 
-                let output = match ty {
-                    "mouse" => match MouseInput::from_string(sub_ty, "10") {
-                        Some(input) => ComputerInput::Mouse(input),
-                        None => {
-                            diagnostics.push(ParseDiagnostic {
-                                input: source.clone(),
-                                span: node.span(),
-                                message: Some(format!("Unknown output {output:?}")),
-                                label: None,
-                                help: None,
-                                severity: miette::Severity::Error,
-                            });
-                            return Parsed {
-                                value: Default::default(),
-                                full_span: node.span(),
-                                name_span: node.span(),
-                                valid: false,
-                            };
-                        }
-                    },
-                    "keyboard" => match keyboard_from_string_kebab(sub_ty) {
-                        Some(input) => ComputerInput::Keyboard(input),
-                        None => {
-                            diagnostics.push(ParseDiagnostic {
-                                input: source.clone(),
-                                span: node.span(),
-                                message: Some(format!("Unknown output {output:?}")),
-                                label: None,
-                                help: None,
-                                severity: miette::Severity::Error,
-                            });
-                            return Parsed {
-                                value: Default::default(),
-                                full_span: node.span(),
-                                name_span: node.span(),
-                                valid: false,
-                            };
-                        }
-                    },
-                    _ => {
+                // e.g. `- "button-left -> keyboard-f9" release="keyboard-f10"`
+                let mut release_output = ArrayVec::new();
+                if let Some(release) = node.get("release") {
+                    let Some(release) = release.as_string().and_then(parse_output) else {
                         diagnostics.push(ParseDiagnostic {
                             input: source.clone(),
                             span: node.span(),
-                            message: Some(format!("Unknown output {output:?}")),
+                            message: Some(format!("Unknown release output {release}")),
                             label: None,
                             help: None,
                             severity: miette::Severity::Error,
@@ -236,11 +339,17 @@ impl KdlConfig for MappingKdl {
                             name_span: node.span(),
                             valid: false,
                         };
-                    }
-                };
-                let output = ArrayVec::from_iter([output]);
+                    };
+                    release_output.push(release);
+                }
+
                 Parsed {
-                    value: MappingKdl { input, output },
+                    value: MappingKdl {
+                        trigger,
+                        input: inputs,
+                        output,
+                        release_output,
+                    },
                     full_span: node.span(),
                     name_span: node.span(),
                     valid: true,
@@ -268,7 +377,31 @@ impl KdlConfig for MappingKdl {
     }
 }
 
+/// Parses a single output such as `keyboard-page-up`, `mouse-scroll-up` or `control-next-profile`.
+/// Mouse outputs may be followed by a value, e.g. `mouse-scroll-up 20`, otherwise the value defaults to 10.
+fn parse_output(output: &str) -> Option<ComputerInput> {
+    let (ty, sub_ty) = output.split_once('-')?;
+    match ty {
+        "mouse" => {
+            let (sub_ty, value) = match sub_ty.split_once(' ') {
+                Some((sub_ty, value)) => (sub_ty, value.trim()),
+                None => (sub_ty, "10"),
+            };
+            MouseInput::from_string(sub_ty, value).map(ComputerInput::Mouse)
+        }
+        "keyboard" => keyboard_from_string_kebab(sub_ty).map(ComputerInput::Keyboard),
+        "control" => {
+            DPedalControl::from_string(&kebab_to_pascal_case(sub_ty)).map(ComputerInput::Control)
+        }
+        _ => None,
+    }
+}
+
 pub fn keyboard_from_string_kebab(s: &str) -> Option<KeyboardInput> {
+    KeyboardInput::from_str(&kebab_to_pascal_case(s)).ok()
+}
+
+fn kebab_to_pascal_case(s: &str) -> String {
     let mut pascal_case = String::new();
 
     let mut upper = true;
@@ -282,7 +415,7 @@ pub fn keyboard_from_string_kebab(s: &str) -> Option<KeyboardInput> {
             pascal_case.push(char);
         }
     }
-    KeyboardInput::from_str(&pascal_case).ok()
+    pascal_case
 }
 
 #[test]
@@ -304,6 +437,14 @@ pub enum DpedalInputKdl {
     DpadRight,
     ButtonLeft,
     ButtonRight,
+    Aux1,
+    Aux2,
+    Aux3,
+    Aux4,
+    Aux5,
+    Aux6,
+    Aux7,
+    Aux8,
 }
 
 #[derive(KdlConfig, KdlConfigFinalize, Default, Debug)]
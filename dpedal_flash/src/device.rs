@@ -0,0 +1,96 @@
+use dpedal_config::web_config_protocol::{DeviceInfo, MAX_MESSAGE_SIZE, Request, Response};
+use miette::{Result, miette};
+use postcard::accumulator::{CobsAccumulator, FeedResult};
+use rusb::{Context, DeviceHandle, Direction, TransferType, UsbContext};
+use std::time::Duration;
+
+/// The interface that the firmware speaks the web config protocol on.
+const WEB_CONFIG_INTERFACE: u8 = 1;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Asks a dpedal that is currently running its firmware which firmware that is.
+///
+/// Returns `None` if no dpedal is running its firmware, e.g. because it is already in BOOTSEL mode,
+/// or if its firmware predates `Request::GetDeviceInfo`.
+pub fn query_device_info(ctx: &Context) -> Result<Option<DeviceInfo>> {
+    let Some(handle) = ctx.open_device_with_vid_pid(0xc0de, 0xcafe) else {
+        return Ok(None);
+    };
+    let (read_ep, write_ep) = web_config_endpoints(&handle)?;
+    handle
+        .claim_interface(WEB_CONFIG_INTERFACE)
+        .map_err(|e| miette!(e).context("could not claim the web config interface"))?;
+
+    // `GetDeviceInfo` is accepted before a handshake, so that it works no matter which protocol the firmware speaks.
+    let request = postcard::to_stdvec_cobs(&Request::GetDeviceInfo)
+        .map_err(|e| miette!(e).context("could not encode request"))?;
+    handle
+        .write_bulk(write_ep, &request, TIMEOUT)
+        .map_err(|e| miette!(e).context("could not send request to device"))?;
+
+    let response = receive_response(&handle, read_ep);
+    let _ = handle.release_interface(WEB_CONFIG_INTERFACE);
+    match response? {
+        Response::GetDeviceInfo(device_info) => Ok(Some(device_info)),
+        Response::ProtocolError => Ok(None),
+        response => Err(miette!(
+            "Device responded to GetDeviceInfo with {response:?}"
+        )),
+    }
+}
+
+/// Returns the addresses of the bulk in and bulk out endpoints of the web config interface.
+fn web_config_endpoints(handle: &DeviceHandle<Context>) -> Result<(u8, u8)> {
+    let config = handle
+        .device()
+        .active_config_descriptor()
+        .map_err(|e| miette!(e).context("could not read device configuration"))?;
+    let mut read_ep = None;
+    let mut write_ep = None;
+    for interface in config.interfaces() {
+        for descriptor in interface.descriptors() {
+            if descriptor.interface_number() != WEB_CONFIG_INTERFACE {
+                continue;
+            }
+            for endpoint in descriptor.endpoint_descriptors() {
+                if endpoint.transfer_type() == TransferType::Bulk {
+                    match endpoint.direction() {
+                        Direction::In => read_ep = Some(endpoint.address()),
+                        Direction::Out => write_ep = Some(endpoint.address()),
+                    }
+                }
+            }
+        }
+    }
+    read_ep
+        .zip(write_ep)
+        .ok_or_else(|| miette!("Device has no web config endpoints"))
+}
+
+fn receive_response(handle: &DeviceHandle<Context>, read_ep: u8) -> Result<Response> {
+    let mut cobs_buf: CobsAccumulator<MAX_MESSAGE_SIZE> = CobsAccumulator::new();
+    let mut buf = [0; 64];
+    loop {
+        let len = handle
+            .read_bulk(read_ep, &mut buf, TIMEOUT)
+            .map_err(|e| miette!(e).context("could not receive response from device"))?;
+        let mut window = &buf[..len];
+        while !window.is_empty() {
+            window = match cobs_buf.feed::<Response>(window) {
+                FeedResult::Consumed => break,
+                FeedResult::OverFull(_) => {
+                    return Err(miette!("Device sent response > {MAX_MESSAGE_SIZE} bytes"));
+                }
+                FeedResult::DeserError(_) => {
+                    return Err(miette!("Device sent response that could not be parsed."));
+                }
+                // Left over from a configurator that subscribed and then went away.
+                FeedResult::Success {
+                    data: Response::InputEvent(_),
+                    remaining,
+                } => remaining,
+                FeedResult::Success { data, .. } => return Ok(data),
+            };
+        }
+    }
+}
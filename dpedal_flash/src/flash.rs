@@ -1,3 +1,4 @@
+use dpedal_config::storage::CONFIG_REGION_SIZE;
 use dpedal_config::{CONFIG_OFFSET, CONFIG_SIZE, FIRMWARE_OFFSET, FIRMWARE_SIZE};
 use miette::{Result, miette};
 use picoboot_rs::{
@@ -36,7 +37,11 @@ pub fn flash_device(firmware: &[u8], config: &[u8]) -> Result<()> {
     println!("writing {} KB of firmware", firmware.len() as f32 / 1000.0);
     flash_bytes_at_offset(&mut conn, firmware, FIRMWARE_OFFSET);
     println!("writing {} KB of config", config.len() as f32 / 1000.0);
-    flash_bytes_at_offset(&mut conn, config, CONFIG_OFFSET);
+    // The config is written to the first slot and every other slot is zeroed,
+    // so the firmware cant pick up a config left over in another slot.
+    let mut config_region = config.to_vec();
+    config_region.resize(CONFIG_REGION_SIZE, 0);
+    flash_bytes_at_offset(&mut conn, &config_region, CONFIG_OFFSET);
 
     // reboot device to start firmware
     let delay = 500; // in milliseconds
@@ -0,0 +1,4 @@
+pub mod config;
+pub mod device;
+pub mod elf;
+pub mod flash;
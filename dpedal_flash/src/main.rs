@@ -1,11 +1,11 @@
 use clap::Parser;
 use dpedal_config::CONFIG_SIZE;
-use miette::Result;
+use dpedal_config::web_config_protocol::PROTOCOL_VERSION;
+use dpedal_flash::{config, device, elf, flash};
+use miette::{Result, miette};
+use rusb::Context;
 
-pub mod cli;
-pub mod config;
-pub mod elf;
-pub mod flash;
+mod cli;
 
 fn main() -> Result<()> {
     // TODO: use this once -Z bindeps stabilizes
@@ -15,11 +15,46 @@ fn main() -> Result<()> {
     let firmware_bytes = elf::elf_to_bin(include_bytes!(env!("FIRMWARE_PATH")))?;
 
     let cli = cli::Args::parse();
+
+    let ctx = Context::new().map_err(|e| miette!(e).context("could not initialize libusb"))?;
+    match device::query_device_info(&ctx) {
+        Ok(Some(info))
+            if info.firmware_version.as_str() == env!("FIRMWARE_VERSION")
+                && info.protocol_version == PROTOCOL_VERSION =>
+        {
+            if !cli.force {
+                println!(
+                    "Device is already running firmware {} with protocol version {}, no upgrade is needed.",
+                    info.firmware_version, info.protocol_version
+                );
+                println!(
+                    "Use the web configurator to change its config, or pass --force to flash it anyway."
+                );
+                return Ok(());
+            }
+        }
+        Ok(Some(info)) => println!(
+            "Device is running firmware {} with protocol version {}, upgrading to firmware {} with protocol version {}",
+            info.firmware_version,
+            info.protocol_version,
+            env!("FIRMWARE_VERSION"),
+            PROTOCOL_VERSION
+        ),
+        Ok(None) => {}
+        Err(e) => println!("Warning: could not query the firmware running on the device: {e:?}"),
+    }
+
     let config_bytes = if cli.erase_config {
         vec![0; CONFIG_SIZE]
     } else {
         let config = config::load(cli.path)?;
-        config::encode_config(&config)?
+        let config_bytes = config::encode_config(&config)?;
+        println!(
+            "Config uses {} of {} bytes",
+            config_bytes.len(),
+            CONFIG_SIZE
+        );
+        config_bytes
     };
     flash::flash_device(&firmware_bytes, &config_bytes)?;
 
@@ -0,0 +1,16 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Runs the DPedal mapping logic against simulated inputs and prints the resulting HID reports.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Path to the dpedal .kdl config file.
+    /// If not specified, loads config from `config.kdl` located in the same directory as the exe/binary.
+    #[arg()]
+    pub path: Option<PathBuf>,
+    /// Path to a script of inputs to simulate, one `<time in ms> <command> <input>` per line.
+    /// If not specified, `<command> <input>` lines are read from stdin and simulated in real time.
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+}
@@ -0,0 +1,83 @@
+use clap::Parser;
+use dpedal_config::storage::access_config;
+use dpedal_engine::DEBOUNCE_MS;
+use dpedal_flash::config;
+use miette::{IntoDiagnostic, Result, miette};
+use script::Command;
+use simulator::Simulator;
+use std::io::BufRead;
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+
+mod cli;
+mod script;
+mod simulator;
+
+fn main() -> Result<()> {
+    let cli = cli::Args::parse();
+    let config = config::load(cli.path)?;
+    let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&config).map_err(|e| miette!(e))?;
+    let config = access_config(&bytes).map_err(|e| miette!("Failed to access config: {e:?}"))?;
+    let mut simulator = Simulator::new(config);
+
+    match cli.script {
+        Some(path) => {
+            let script = std::fs::read_to_string(&path)
+                .into_diagnostic()
+                .map_err(|e| e.context(format!("Failed to load script at {path:?}")))?;
+            run_script(&mut simulator, &script::parse(&script)?);
+        }
+        None => run_interactive(&mut simulator),
+    }
+    Ok(())
+}
+
+/// Runs as fast as possible, until every event has been applied and the inputs have settled.
+fn run_script(simulator: &mut Simulator, events: &[script::Event]) {
+    let mut events = events.iter().peekable();
+    while events.peek().is_some() || !simulator.is_settled() {
+        while let Some(event) = events.next_if(|event| event.time <= simulator.now()) {
+            simulator.apply(event.command);
+        }
+        simulator.tick();
+    }
+}
+
+/// Runs in real time, applying commands as they are entered until stdin is closed.
+fn run_interactive(simulator: &mut Simulator) {
+    println!(
+        "Enter commands such as `press dpad-up`, `release dpad-up` or `tap encoder1-clockwise`."
+    );
+    println!("Inputs must be held for at least {DEBOUNCE_MS}ms to be registered.");
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Command::parse(&line) {
+                Ok(command) => sender.send(command).unwrap(),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+    });
+
+    let start = Instant::now();
+    loop {
+        loop {
+            match receiver.try_recv() {
+                Ok(command) => simulator.apply(command),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        simulator.tick();
+
+        let next_tick = start + Duration::from_millis(simulator.now());
+        std::thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+    }
+}
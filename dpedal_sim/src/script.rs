@@ -0,0 +1,104 @@
+use dpedal_config::DpedalInput;
+use dpedal_engine::Millis;
+use miette::{Result, miette};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Command {
+    Press(DpedalInput),
+    Release(DpedalInput),
+    /// Press and immediately release, like a rotary encoder detent.
+    Tap(DpedalInput),
+    /// Does nothing, used to keep the simulation running until the given time.
+    Wait,
+}
+
+impl Command {
+    /// Parses a command such as `press dpad-up`, `release dpad-up`, `tap encoder1-clockwise` or `wait`.
+    pub fn parse(command: &str) -> Result<Self> {
+        let mut words = command.split_whitespace();
+        let name = words.next().ok_or_else(|| miette!("Expected a command"))?;
+        if name == "wait" {
+            return Ok(Command::Wait);
+        }
+
+        let input = words
+            .next()
+            .ok_or_else(|| miette!("Expected an input after {name:?}"))?;
+        let input = DpedalInput::from_string_kebab(input)
+            .ok_or_else(|| miette!("Unknown input {input:?}"))?;
+        match name {
+            "press" => Ok(Command::Press(input)),
+            "release" => Ok(Command::Release(input)),
+            "tap" => Ok(Command::Tap(input)),
+            _ => Err(miette!(
+                "Unknown command {name:?}, expected press, release, tap or wait"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub time: Millis,
+    pub command: Command,
+}
+
+/// Parses a script of `<time in ms> <command>` lines.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse(script: &str) -> Result<Vec<Event>> {
+    let mut events: Vec<Event> = vec![];
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |e: miette::Report| miette!("Line {}: {e}", i + 1);
+        let (time, command) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| error(miette!("Expected `<time in ms> <command>`")))?;
+        let time: Millis = time
+            .parse()
+            .map_err(|_| error(miette!("Invalid time {time:?}")))?;
+        if let Some(last) = events.last()
+            && time < last.time
+        {
+            return Err(error(miette!(
+                "Time {time} is before the previous line's time {}",
+                last.time
+            )));
+        }
+        let command = Command::parse(command).map_err(error)?;
+        events.push(Event { time, command });
+    }
+    Ok(events)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        parse("# hold up\n0 press dpad-up\n\n100 release dpad-up\n150 tap encoder1-clockwise\n500 wait")
+            .unwrap(),
+        vec![
+            Event {
+                time: 0,
+                command: Command::Press(DpedalInput::DpadUp)
+            },
+            Event {
+                time: 100,
+                command: Command::Release(DpedalInput::DpadUp)
+            },
+            Event {
+                time: 150,
+                command: Command::Tap(DpedalInput::Encoder1Clockwise)
+            },
+            Event {
+                time: 500,
+                command: Command::Wait
+            },
+        ]
+    );
+    assert!(parse("100 press dpad-up\n50 release dpad-up").is_err());
+    assert!(parse("0 press pedal").is_err());
+    assert!(parse("0 hold dpad-up").is_err());
+}
@@ -0,0 +1,135 @@
+use crate::script::Command;
+use dpedal_config::{ArchivedConfig, ComputerInput};
+use dpedal_engine::report::{
+    KeyboardReport, KeyboardReportBuilder, MouseReport, MouseReportBuilder,
+};
+use dpedal_engine::{Action, Engine, InputState, Millis};
+
+/// Drives the engine the same way the firmware does, with one tick per USB poll.
+pub struct Simulator<'a> {
+    config: &'a ArchivedConfig,
+    engine: Engine,
+    inputs: InputState,
+    keyboard: KeyboardReportBuilder,
+    mouse: MouseReportBuilder,
+    last_keyboard_report: KeyboardReport,
+    last_mouse_buttons: u8,
+    now: Millis,
+}
+
+impl<'a> Simulator<'a> {
+    pub fn new(config: &'a ArchivedConfig) -> Self {
+        let mut engine = Engine::new(0);
+        engine.config_changed(config);
+        Simulator {
+            config,
+            engine,
+            inputs: InputState::default(),
+            keyboard: KeyboardReportBuilder::default(),
+            mouse: MouseReportBuilder::default(),
+            last_keyboard_report: KeyboardReport::default(),
+            last_mouse_buttons: 0,
+            now: 0,
+        }
+    }
+
+    pub fn now(&self) -> Millis {
+        self.now
+    }
+
+    /// Returns true once all inputs have settled.
+    pub fn is_settled(&self) -> bool {
+        self.engine.next_deadline().is_none()
+    }
+
+    pub fn apply(&mut self, command: Command) {
+        match command {
+            Command::Press(input) => self.inputs.set_pressed(input),
+            Command::Release(input) => self.inputs.set_released(input),
+            Command::Tap(input) => {
+                self.engine.tap(self.config, input, self.now);
+                self.perform_actions();
+            }
+            Command::Wait => {}
+        }
+    }
+
+    /// Advances the simulation by one millisecond, printing any reports that would be sent to the computer.
+    pub fn tick(&mut self) {
+        self.engine.update(self.config, self.inputs, self.now);
+        self.perform_actions();
+        self.write_keyboard_report();
+        self.write_mouse_report();
+        self.now += 1;
+    }
+
+    fn perform_actions(&mut self) {
+        while let Some(action) = self.engine.next_action(self.config) {
+            // Like the firmware, send any change the host has not seen yet before it is undone.
+            match action {
+                Action::Pressed(ComputerInput::Keyboard(key))
+                | Action::Released(ComputerInput::Keyboard(key))
+                    if self.keyboard.is_unreported(key) =>
+                {
+                    self.write_keyboard_report()
+                }
+                Action::Pressed(ComputerInput::Mouse(mouse))
+                | Action::Released(ComputerInput::Mouse(mouse))
+                    if self.mouse.is_unreported(mouse) =>
+                {
+                    self.write_mouse_report()
+                }
+                _ => {}
+            }
+
+            match action {
+                Action::Pressed(ComputerInput::Keyboard(key)) => self.keyboard.pressed(key),
+                Action::Released(ComputerInput::Keyboard(key)) => self.keyboard.released(key),
+                Action::Pressed(ComputerInput::Mouse(mouse)) => self.mouse.pressed(mouse),
+                Action::Released(ComputerInput::Mouse(mouse)) => self.mouse.released(mouse),
+                Action::Pressed(_) | Action::Released(_) => {}
+                Action::ProfileChanged(profile) => {
+                    println!("{:>7}ms profile {profile} is now active", self.now)
+                }
+                Action::ReleaseAll => {
+                    self.keyboard.release_all();
+                    self.mouse.release_all();
+                }
+            }
+        }
+        for (profile, mapping) in self.engine.take_triggered_mappings() {
+            println!(
+                "{:>7}ms mapping {mapping} of profile {profile} triggered",
+                self.now
+            );
+        }
+    }
+
+    /// Prints the keyboard report if it differs from the previous one.
+    fn write_keyboard_report(&mut self) {
+        let keyboard = self.keyboard.next_report();
+        if keyboard != self.last_keyboard_report {
+            println!(
+                "{:>7}ms keyboard modifier: {:#04x} keycodes: {:02x?}",
+                self.now, keyboard.modifier, keyboard.keycodes
+            );
+            self.last_keyboard_report = keyboard;
+        }
+    }
+
+    /// Prints the mouse report if it moves or its buttons differ from the previous one.
+    fn write_mouse_report(&mut self) {
+        let mouse = self.mouse.next_report();
+        let idle = MouseReport {
+            buttons: self.last_mouse_buttons,
+            ..Default::default()
+        };
+        if mouse != idle {
+            println!(
+                "{:>7}ms mouse buttons: {:#05b} x: {} y: {} wheel: {} pan: {}",
+                self.now, mouse.buttons, mouse.x, mouse.y, mouse.wheel, mouse.pan
+            );
+            self.last_mouse_buttons = mouse.buttons;
+        }
+    }
+}